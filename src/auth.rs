@@ -0,0 +1,143 @@
+use crate::config::{parse_hex32, Hex};
+use crate::identity::{backup_path, check_permissions, create_state_dir, write_private_file};
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+const SECRET_FILE: &str = "quictor_auth_secret";
+
+/// Domain separation for the HMAC in stream tokens.
+const TOKEN_LABEL: &[u8] = b"quictor-pt stream auth v1";
+const NONCE_LEN: usize = 16;
+const TOKEN_LEN: usize = NONCE_LEN + 32;
+
+/// Per-bridge secret that clients must prove they know before the server
+/// relays anything. It travels in bridge lines as `auth=`, so only people
+/// handed a bridge line can use the bridge; a scanner that finds the port
+/// can complete the QUIC handshake but gets no further.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AuthSecret([u8; 32]);
+
+impl AuthSecret {
+    /// Loads the secret from `state_dir`, creating it on first start.
+    pub fn load_or_create(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(SECRET_FILE);
+        if path.exists() {
+            return Self::load(&path);
+        }
+
+        Self::create(state_dir)
+    }
+
+    /// Replaces the secret in `state_dir`, keeping the old one with a timestamped `.old` suffix.
+    pub fn rotate(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(SECRET_FILE);
+        if path.exists() {
+            let backup = backup_path(&path);
+            std::fs::rename(&path, &backup)
+                .context(format!("Failed to move {} to {}", path.display(), backup.display()))?;
+        }
+
+        Self::create(state_dir)
+    }
+
+    /// Loads the secret from `state_dir`, failing if there is none yet.
+    pub fn load_existing(state_dir: &Path) -> Result<Self> {
+        if !Self::exists(state_dir) {
+            anyhow::bail!(
+                "No auth secret in {}; run `quictor-pt keygen` or start the server once",
+                state_dir.display()
+            );
+        }
+
+        Self::load(&state_dir.join(SECRET_FILE))
+    }
+
+    pub fn exists(state_dir: &Path) -> bool {
+        state_dir.join(SECRET_FILE).exists()
+    }
+
+    fn generate() -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        aws_lc_rs::rand::fill(&mut bytes)
+            .map_err(|_| anyhow::anyhow!("Failed to generate auth secret"))?;
+        Ok(AuthSecret(bytes))
+    }
+
+    /// A fresh token for a stream header: a random nonce and its HMAC.
+    pub fn token(&self) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        aws_lc_rs::rand::fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("Failed to generate auth nonce"))?;
+
+        let mut token = nonce.to_vec();
+        token.extend_from_slice(self.sign(&nonce).as_ref());
+
+        Ok(token)
+    }
+
+    /// Whether `token` was made with this secret. The comparison is constant-time.
+    pub fn verify(&self, token: &[u8]) -> bool {
+        use aws_lc_rs::hmac;
+
+        if token.len() != TOKEN_LEN {
+            return false;
+        }
+        let (nonce, tag) = token.split_at(NONCE_LEN);
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.0);
+        hmac::verify(&key, &[TOKEN_LABEL, nonce].concat(), tag).is_ok()
+    }
+
+    fn sign(&self, nonce: &[u8]) -> aws_lc_rs::hmac::Tag {
+        use aws_lc_rs::hmac;
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.0);
+        hmac::sign(&key, &[TOKEN_LABEL, nonce].concat())
+    }
+
+    fn create(state_dir: &Path) -> Result<Self> {
+        create_state_dir(state_dir)?;
+
+        let secret = Self::generate()?;
+        write_private_file(&state_dir.join(SECRET_FILE), &format!("{}\n", secret))?;
+
+        tracing::info!("Created new bridge auth secret in {}", state_dir.display());
+
+        Ok(secret)
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        check_permissions(path, 0o077)?;
+
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Failed to read {}", path.display()))?;
+
+        contents.trim().parse()
+            .context(format!("Invalid auth secret in {}", path.display()))
+    }
+}
+
+impl fmt::Display for AuthSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}", Hex(&self.0))
+    }
+}
+
+/// Never prints the secret itself, since `Debug` output ends up in logs.
+impl fmt::Debug for AuthSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthSecret(..)")
+    }
+}
+
+impl FromStr for AuthSecret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_hex32(s)
+            .map(AuthSecret)
+            .context("Auth secret must be 64 hex characters")
+    }
+}
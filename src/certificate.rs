@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose, SerialNumber,
+};
+use rustls::pki_types::CertificateDer;
+use std::str::FromStr;
+use time::{Duration, OffsetDateTime};
+
+/// Lifetimes of commonly seen server certificates, Let's Encrypt's 90 days most of all.
+const VALIDITY_DAYS: &[i64] = &[90, 90, 90, 90, 180, 365, 397];
+
+/// Longest lifetime an operator may ask for, which keeps certificate dates
+/// well within what X.509 and `time` can represent.
+pub const MAX_VALIDITY_DAYS: u32 = 3650;
+
+const COUNTRIES: &[&str] = &["US", "US", "GB", "DE", "NL", "FR", "CA", "SE", "CH", "JP"];
+
+const ISSUER_NAMES: &[&str] = &[
+    "Northgate", "Bluefield", "Cedarline", "Halcyon", "Meridian", "Sterling", "Harborview", "Summit",
+    "Keystone", "Evergreen", "Lakeside", "Ironwood", "Silverpine", "Brightpath", "Crestview", "Redstone",
+];
+
+const ISSUER_SUFFIXES: &[&str] = &[
+    "Trust Services", "Certificate Authority", "Digital Trust", "Security", "Networks", "Internet Services",
+];
+
+const NAME_PREFIXES: &[&str] = &[
+    "blue", "quiet", "bright", "north", "silver", "green", "swift", "open", "urban", "little", "amber",
+    "cobalt", "maple", "sunny", "lucky", "paper", "copper", "misty", "golden", "hidden",
+];
+
+const NAME_WORDS: &[&str] = &[
+    "harbor", "field", "stone", "cloud", "river", "pixel", "leaf", "garden", "studio", "works", "labs",
+    "notes", "kitchen", "forge", "media", "atlas", "press", "market", "trail", "design",
+];
+
+const TOP_LEVEL_DOMAINS: &[&str] = &["com", "com", "com", "net", "org", "io", "dev", "co", "app", "info"];
+
+/// Key algorithm for generated server keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyType {
+    #[default]
+    P256,
+    Ed25519,
+    Rsa2048,
+}
+
+impl KeyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::P256 => "p256",
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Rsa2048 => "rsa2048",
+        }
+    }
+
+    fn algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        match self {
+            KeyType::P256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+            KeyType::Rsa2048 => &rcgen::PKCS_RSA_SHA256,
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "p256" => Ok(KeyType::P256),
+            "ed25519" => Ok(KeyType::Ed25519),
+            "rsa2048" => Ok(KeyType::Rsa2048),
+            _ => anyhow::bail!("Unknown key type '{}' (expected p256, ed25519 or rsa2048)", s),
+        }
+    }
+}
+
+/// How generated certificates look. What is left unset is picked at random
+/// for every certificate, so bridges do not share a recognizable profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CertProfile {
+    pub key_type: KeyType,
+    /// Organization named as the issuer.
+    pub issuer: Option<String>,
+    /// How long certificates are valid for, in days.
+    pub validity_days: Option<u32>,
+    /// Whether to issue from a generated intermediate CA rather than self-sign.
+    pub chain: bool,
+}
+
+impl CertProfile {
+    pub fn generate_key(&self) -> Result<KeyPair> {
+        KeyPair::generate_for(self.key_type.algorithm())
+            .context(format!("Failed to generate {} key", self.key_type.as_str()))
+    }
+
+    /// A certificate chain for `names` and `key`, leaf first.
+    ///
+    /// With `chain`, the leaf is issued by an intermediate CA, which is issued
+    /// by a root that is not sent, as servers with a public CA certificate do.
+    /// The CA keys are thrown away; clients pin the leaf's key, not the chain.
+    pub fn issue(&self, key: &KeyPair, names: &[String]) -> Result<Vec<CertificateDer<'static>>> {
+        let validity = match self.validity_days {
+            Some(days) => i64::from(days),
+            None => *pick(VALIDITY_DAYS),
+        };
+        // Backdated as if issued a while ago, but by less than a third of the
+        // lifetime, so that short-lived certificates are not close to expiry.
+        let backdate = Duration::days(30).min(Duration::days(validity) / 3);
+        let not_before = OffsetDateTime::now_utc() - random_duration(Duration::hours(1), backdate);
+
+        let mut leaf = CertificateParams::new(names.to_vec())
+            .context("Invalid certificate name")?;
+        leaf.serial_number = Some(random_serial());
+        leaf.not_before = not_before;
+        leaf.not_after = not_before + Duration::days(validity);
+        leaf.distinguished_name = DistinguishedName::new();
+        if let Some(name) = names.first() {
+            leaf.distinguished_name.push(DnType::CommonName, name.as_str());
+        }
+        leaf.key_usages = match self.key_type {
+            KeyType::Rsa2048 => vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment],
+            _ => vec![KeyUsagePurpose::DigitalSignature],
+        };
+        leaf.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+
+        let organization = self.issuer.clone().unwrap_or_else(random_issuer);
+
+        if !self.chain {
+            leaf.distinguished_name.push(DnType::OrganizationName, organization);
+            let cert = leaf.self_signed(key)
+                .context("Failed to sign server certificate")?;
+            return Ok(vec![cert.der().clone()]);
+        }
+
+        let country = *pick(COUNTRIES);
+        let short_name = organization.split_whitespace().next().unwrap_or("Trust").to_string();
+
+        let root_key = self.generate_key()?;
+        let mut root = ca_params(country, &organization, &format!("{} Root CA", short_name))?;
+        root.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        root.not_before = not_before - random_duration(Duration::days(4 * 365), Duration::days(10 * 365));
+        root.not_after = root.not_before + Duration::days(25 * 365);
+        let root = root.self_signed(&root_key)
+            .context("Failed to sign root certificate")?;
+
+        let intermediate_key = self.generate_key()?;
+        let generation = 1 + random_below(9);
+        let mut intermediate = ca_params(
+            country,
+            &organization,
+            &format!("{} TLS Issuing CA {}", short_name, generation),
+        )?;
+        intermediate.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        intermediate.not_before = not_before - random_duration(Duration::days(180), Duration::days(3 * 365));
+        intermediate.not_after = leaf.not_after + random_duration(Duration::days(365), Duration::days(3 * 365));
+        intermediate.use_authority_key_identifier_extension = true;
+        let intermediate = intermediate.signed_by(&intermediate_key, &root, &root_key)
+            .context("Failed to sign intermediate certificate")?;
+
+        leaf.use_authority_key_identifier_extension = true;
+        let leaf = leaf.signed_by(key, &intermediate, &intermediate_key)
+            .context("Failed to sign server certificate")?;
+
+        Ok(vec![leaf.der().clone(), intermediate.der().clone()])
+    }
+}
+
+/// A made-up but ordinary-looking domain name, for bridges that set none.
+pub fn random_server_name() -> String {
+    format!("{}{}.{}", pick(NAME_PREFIXES), pick(NAME_WORDS), pick(TOP_LEVEL_DOMAINS))
+}
+
+fn random_issuer() -> String {
+    format!("{} {}", pick(ISSUER_NAMES), pick(ISSUER_SUFFIXES))
+}
+
+fn ca_params(country: &str, organization: &str, common_name: &str) -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.serial_number = Some(random_serial());
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CountryName, country);
+    params.distinguished_name.push(DnType::OrganizationName, organization);
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+    ];
+    Ok(params)
+}
+
+/// 16 random bytes, kept positive as DER integers must be.
+fn random_serial() -> SerialNumber {
+    let mut serial = random_bytes::<16>();
+    serial[0] &= 0x7f;
+    serial[0] |= 0x01;
+    SerialNumber::from_slice(&serial)
+}
+
+fn random_duration(min: Duration, max: Duration) -> Duration {
+    let span = (max - min).whole_seconds().max(1) as u64;
+    min + Duration::seconds((random_u64() % span) as i64)
+}
+
+fn random_below(bound: usize) -> usize {
+    (random_u64() % bound as u64) as usize
+}
+
+fn pick<T>(items: &[T]) -> &T {
+    &items[random_below(items.len())]
+}
+
+fn random_u64() -> u64 {
+    u64::from_be_bytes(random_bytes())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    // Only fails if the system RNG is unusable, in which case nothing else works either.
+    aws_lc_rs::rand::fill(&mut bytes).expect("system random number generator failed");
+    bytes
+}
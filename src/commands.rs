@@ -0,0 +1,341 @@
+use crate::auth::AuthSecret;
+use crate::config::ServerOptions;
+use crate::identity::ServerIdentity;
+use crate::pt::bridge::{BridgeLine, BridgeParams};
+use crate::pt::env::{ClientEnv, ProxyConfig, ProxyKind, ServerEnv};
+use crate::pt::extorport::ExtOrPort;
+use crate::pt::transport::{self, Transport, DEFAULT_TRANSPORT};
+use crate::standalone::{merge_options, ConfigFile};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Flags for `quictor-pt keygen`.
+#[derive(Debug, Clone, clap::Args)]
+pub struct KeygenFlags {
+    /// State directory to write the identity to (Tor's TOR_PT_STATE_LOCATION)
+    #[arg(long)]
+    pub state_dir: PathBuf,
+    /// Name to put in the certificate [default: a random domain name]
+    #[arg(long)]
+    pub sni: Option<String>,
+    /// Certificate option as KEY=VALUE (sni, cert-key, cert-issuer, cert-validity, cert-chain); may be repeated
+    #[arg(long = "option", value_name = "KEY=VALUE")]
+    pub options: Vec<String>,
+    /// Replace an existing identity and auth secret, keeping the old files with a timestamped .old suffix
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Flags for `quictor-pt bridge-line`.
+#[derive(Debug, Clone, clap::Args)]
+pub struct BridgeLineFlags {
+    /// Public address clients should dial, as IP:PORT
+    #[arg(long)]
+    pub address: SocketAddr,
+    /// TOML config file with a [server] section
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// State directory holding the server identity
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+    /// Transport the bridge runs [default: quictor_h3]
+    #[arg(long)]
+    pub transport: Option<String>,
+    /// Server option as KEY=VALUE, as given to the server; may be repeated
+    #[arg(long = "option", value_name = "KEY=VALUE")]
+    pub options: Vec<String>,
+    /// Tor relay identity fingerprint [default: read from Tor's data directory]
+    #[arg(long)]
+    pub fingerprint: Option<String>,
+}
+
+/// Creates the persistent server identity and auth secret and prints the
+/// bridge line arguments that pin them.
+pub fn keygen(flags: KeygenFlags) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let mut args = merge_options(Default::default(), &flags.options)?;
+    if let Some(sni) = &flags.sni {
+        args.insert("sni", sni.as_str());
+    }
+    let options = ServerOptions::default().with_args(&args)
+        .context("Invalid certificate options")?;
+    if options.cert_file.is_some() {
+        anyhow::bail!("An operator-supplied certificate needs no keygen; give cert-file and key-file to the server");
+    }
+
+    let server_names = options.names_for_new_identity();
+
+    let (identity, auth) = if ServerIdentity::exists(&flags.state_dir) {
+        if !flags.force {
+            anyhow::bail!(
+                "A server identity already exists in {}; use --force to replace it",
+                flags.state_dir.display()
+            );
+        }
+        (
+            ServerIdentity::rotate(&flags.state_dir, &server_names, &options.certificate)?,
+            AuthSecret::rotate(&flags.state_dir)?,
+        )
+    } else {
+        (
+            ServerIdentity::load_or_create(&flags.state_dir, &server_names, &options.certificate)?,
+            AuthSecret::load_or_create(&flags.state_dir)?,
+        )
+    };
+
+    println!("cert={} auth={}", identity.fingerprint(), auth);
+
+    Ok(())
+}
+
+/// Prints a `Bridge ...` line for the identity in the state directory.
+pub fn bridge_line(flags: BridgeLineFlags) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let file = match &flags.config {
+        Some(path) => ConfigFile::load(path)?.server.unwrap_or_default(),
+        None => Default::default(),
+    };
+
+    let state_dir = flags.state_dir
+        .or(file.state_dir.map(PathBuf::from))
+        .context("No state directory given; use --state-dir or `state_dir` in [server]")?;
+
+    let transport_name = flags.transport.or(file.transport)
+        .unwrap_or_else(|| DEFAULT_TRANSPORT.to_string());
+    let transport = Transport::lookup(&transport_name)
+        .context(format!("No such transport: {}", transport_name))?;
+
+    let args = merge_options(file.options, &flags.options)?;
+    let options = transport.server_options().with_args(&args)
+        .context("Invalid server options")?;
+
+    let identity = match (&options.cert_file, &options.key_file) {
+        (Some(cert_file), Some(key_file)) => ServerIdentity::from_files(cert_file, key_file)?,
+        _ => ServerIdentity::load_existing(&state_dir)?,
+    };
+    let auth = AuthSecret::load_existing(&state_dir)?;
+    let params = BridgeParams::for_server(&identity, auth, &options);
+
+    let relay_fingerprint = match flags.fingerprint {
+        Some(fingerprint) => Some(fingerprint),
+        None => read_relay_fingerprint(&state_dir),
+    };
+
+    let line = BridgeLine {
+        transport: transport.name.to_string(),
+        address: flags.address.to_string(),
+        relay_fingerprint,
+        args: params.to_args(),
+    };
+
+    println!("{}", line);
+
+    Ok(())
+}
+
+/// Tor keeps PT state in `<DataDirectory>/pt_state`, next to the `fingerprint`
+/// file holding `<nickname> <fingerprint>`.
+fn read_relay_fingerprint(state_dir: &Path) -> Option<String> {
+    let path = state_dir.parent()?.join("fingerprint");
+    let contents = std::fs::read_to_string(&path).ok()?;
+    let fingerprint = contents.split_whitespace().nth(1)?;
+
+    if fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(fingerprint.to_string())
+    } else {
+        tracing::warn!("Ignoring malformed Tor fingerprint file {}", path.display());
+        None
+    }
+}
+
+/// Collects the outcome of each `check` step and prints it as it goes.
+#[derive(Default)]
+struct CheckReport {
+    failures: usize,
+}
+
+impl CheckReport {
+    fn ok(&mut self, message: impl std::fmt::Display) {
+        println!("ok    {}", message);
+    }
+
+    fn fail(&mut self, message: impl std::fmt::Display) {
+        println!("FAIL  {}", message);
+        self.failures += 1;
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.failures > 0 {
+            anyhow::bail!("{} check(s) failed", self.failures);
+        }
+        Ok(())
+    }
+}
+
+/// Validates the `TOR_PT_*` environment that Tor would pass, without binding anything.
+pub fn check_managed() -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+
+    match std::env::var("TOR_PT_MANAGED_TRANSPORT_VER") {
+        Ok(versions) if versions.split(',').any(|v| v.trim() == crate::pt::PT_VERSION) => {
+            report.ok(format!("TOR_PT_MANAGED_TRANSPORT_VER={}", versions));
+        }
+        Ok(versions) => report.fail(format!("Unsupported TOR_PT_MANAGED_TRANSPORT_VER={}", versions)),
+        Err(_) => report.fail("TOR_PT_MANAGED_TRANSPORT_VER not set"),
+    }
+
+    let client = std::env::var("TOR_PT_CLIENT_TRANSPORTS").is_ok();
+    let server = std::env::var("TOR_PT_SERVER_TRANSPORTS").is_ok();
+
+    if client {
+        match ClientEnv::from_env() {
+            Ok(env) => check_client_env(&env, &mut report),
+            Err(e) => report.fail(format!("Client environment: {:#}", e)),
+        }
+    }
+    if server {
+        match ServerEnv::from_env() {
+            Ok(env) => check_server_env(&env, &mut report),
+            Err(e) => report.fail(format!("Server environment: {:#}", e)),
+        }
+    }
+    if !client && !server {
+        report.fail("Neither TOR_PT_CLIENT_TRANSPORTS nor TOR_PT_SERVER_TRANSPORTS is set");
+    }
+
+    report.finish()
+}
+
+/// Validates a standalone client configuration.
+pub fn check_client(env: anyhow::Result<ClientEnv>) -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+    match env {
+        Ok(env) => check_client_env(&env, &mut report),
+        Err(e) => report.fail(format!("Client configuration: {:#}", e)),
+    }
+    report.finish()
+}
+
+/// Validates a standalone server configuration.
+pub fn check_server(env: anyhow::Result<ServerEnv>) -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+    match env {
+        Ok(env) => check_server_env(&env, &mut report),
+        Err(e) => report.fail(format!("Server configuration: {:#}", e)),
+    }
+    report.finish()
+}
+
+fn check_client_env(env: &ClientEnv, report: &mut CheckReport) {
+    let (transports, unknown) = transport::select(&env.transports);
+    for name in &unknown {
+        report.fail(format!("Client transport '{}' is not supported", name));
+    }
+    for transport in &transports {
+        report.ok(format!("Client transport '{}'", transport.name));
+    }
+
+    if let Some(proxy) = &env.proxy {
+        match proxy.parse::<ProxyConfig>() {
+            Ok(config) if config.kind == ProxyKind::Socks5 => {
+                report.ok(format!("Upstream proxy {}", config.addr));
+            }
+            Ok(config) => report.fail(format!(
+                "Upstream proxy {} is {:?}; only socks5 proxies can relay QUIC",
+                config.addr, config.kind
+            )),
+            Err(e) => report.fail(format!("Upstream proxy: {:#}", e)),
+        }
+    }
+}
+
+fn check_server_env(env: &ServerEnv, report: &mut CheckReport) {
+    use crate::pt::server::server_options;
+
+    let (transports, unknown) = transport::select(&env.transports);
+    for name in &unknown {
+        report.fail(format!("Server transport '{}' is not supported", name));
+    }
+
+    let state_dir = Path::new(&env.state_location);
+    let identity = if ServerIdentity::exists(state_dir) {
+        ServerIdentity::load_existing(state_dir).inspect(|identity| {
+            report.ok(format!("Server identity in {}: cert={}", state_dir.display(), identity.fingerprint()));
+        })
+    } else {
+        report.ok(format!("No server identity in {} yet; one is created on first start", state_dir.display()));
+        ServerIdentity::ephemeral(&[crate::certificate::random_server_name()], &Default::default())
+    };
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(e) => {
+            report.fail(format!("Server identity: {:#}", e));
+            return;
+        }
+    };
+
+    if AuthSecret::exists(state_dir) {
+        match AuthSecret::load_existing(state_dir) {
+            Ok(_) => report.ok(format!("Auth secret in {}", state_dir.display())),
+            Err(e) => report.fail(format!("Auth secret: {:#}", e)),
+        }
+    } else {
+        report.ok(format!("No auth secret in {} yet; one is created on first start", state_dir.display()));
+    }
+
+    for transport in transports {
+        let options = match server_options(transport, env) {
+            Ok(options) => options,
+            Err(e) => {
+                report.fail(format!("Options for '{}': {:#}", transport.name, e));
+                continue;
+            }
+        };
+        let provided;
+        let identity = match (&options.cert_file, &options.key_file) {
+            (Some(cert_file), Some(key_file)) => match ServerIdentity::from_files(cert_file, key_file) {
+                Ok(loaded) => {
+                    report.ok(format!("Certificate in {}: cert={}", cert_file.display(), loaded.fingerprint()));
+                    provided = loaded;
+                    &provided
+                }
+                Err(e) => {
+                    report.fail(format!("Certificate for '{}': {:#}", transport.name, e));
+                    continue;
+                }
+            },
+            _ => &identity,
+        };
+        if let Err(e) = crate::config::configure_server(identity, &options) {
+            report.fail(format!("QUIC configuration for '{}': {:#}", transport.name, e));
+            continue;
+        }
+        if let Some(directory) = &options.acme.directory {
+            report.ok(format!(
+                "ACME certificate for {} from {}, {} challenges",
+                options.server_names.join(", "),
+                directory,
+                options.acme.challenge.as_str()
+            ));
+        }
+
+        let bind_addrs: Vec<String> = env.bind_addrs_for(transport.name).iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        if bind_addrs.is_empty() {
+            report.fail(format!("No bind address for '{}'", transport.name));
+        } else {
+            report.ok(format!("Server transport '{}' on {}", transport.name, bind_addrs.join(", ")));
+        }
+    }
+
+    match &env.extended_orport {
+        Some((addr, cookie_file)) => match ExtOrPort::new(*addr, cookie_file, env.outbound_bind) {
+            Ok(_) => report.ok(format!("Extended ORPort {}", addr)),
+            Err(e) => report.fail(format!("Extended ORPort: {:#}", e)),
+        },
+        None => report.ok(format!("ORPort {}", env.orport)),
+    }
+}
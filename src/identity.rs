@@ -0,0 +1,405 @@
+use crate::certificate::{CertProfile, KeyType};
+use crate::config::CertFingerprint;
+use anyhow::{Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::{Path, PathBuf};
+
+const CERT_FILE: &str = "quictor_cert.pem";
+const KEY_FILE: &str = "quictor_key.pem";
+
+/// The server's long-term TLS identity: certificate chain and private key.
+///
+/// Kept under `TOR_PT_STATE_LOCATION` so that the fingerprint clients pin
+/// survives bridge restarts.
+pub struct ServerIdentity {
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    fingerprint: CertFingerprint,
+    /// Whether the operator supplied the certificate, in which case it is
+    /// served as is rather than reissued for other names.
+    provided: bool,
+}
+
+impl ServerIdentity {
+    /// Loads the identity from `state_dir`, creating it on first start with a
+    /// certificate for `server_names` made according to `profile`.
+    pub fn load_or_create(state_dir: &Path, server_names: &[String], profile: &CertProfile) -> Result<Self> {
+        let cert_path = state_dir.join(CERT_FILE);
+        let key_path = state_dir.join(KEY_FILE);
+
+        match (cert_path.exists(), key_path.exists()) {
+            (true, true) => Self::load(&cert_path, &key_path),
+            (false, false) => Self::create(state_dir, server_names, profile),
+            _ => anyhow::bail!(
+                "Incomplete server identity in {}: both {} and {} are required",
+                state_dir.display(),
+                CERT_FILE,
+                KEY_FILE
+            ),
+        }
+    }
+
+    /// Replaces the identity in `state_dir` with a freshly generated one.
+    ///
+    /// The previous files are kept with a timestamped `.old` suffix so that an
+    /// operator can roll back if clients still pin the old fingerprint.
+    pub fn rotate(state_dir: &Path, server_names: &[String], profile: &CertProfile) -> Result<Self> {
+        for name in [CERT_FILE, KEY_FILE] {
+            let path = state_dir.join(name);
+            if path.exists() {
+                let backup = backup_path(&path);
+                std::fs::rename(&path, &backup)
+                    .context(format!("Failed to move {} to {}", path.display(), backup.display()))?;
+            }
+        }
+
+        Self::create(state_dir, server_names, profile)
+    }
+
+    /// Loads an operator's own certificate chain and key, for instance one
+    /// issued by a public CA for a domain the bridge really serves.
+    pub fn from_files(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let identity = Self::load(cert_path, key_path)?;
+        Ok(ServerIdentity { provided: true, ..identity })
+    }
+
+    /// This identity's key with `cert_chain`, such as one a CA issued for it.
+    /// Like an operator's certificate, it is served for every name.
+    pub fn with_cert_chain(&self, cert_chain: Vec<CertificateDer<'static>>) -> Result<Self> {
+        let end_entity = cert_chain.first()
+            .context("Empty certificate chain")?;
+        let fingerprint = CertFingerprint::from_certificate(end_entity)?;
+        if fingerprint != self.fingerprint {
+            anyhow::bail!("Certificate is for a different key (cert={})", fingerprint);
+        }
+
+        Ok(ServerIdentity {
+            cert_chain,
+            key: self.key.clone_key(),
+            fingerprint,
+            provided: true,
+        })
+    }
+
+    /// Loads the identity from `state_dir`, failing if there is none yet.
+    pub fn load_existing(state_dir: &Path) -> Result<Self> {
+        if !Self::exists(state_dir) {
+            anyhow::bail!(
+                "No server identity in {}; run `quictor-pt keygen` or start the server once",
+                state_dir.display()
+            );
+        }
+
+        Self::load(&state_dir.join(CERT_FILE), &state_dir.join(KEY_FILE))
+    }
+
+    pub fn exists(state_dir: &Path) -> bool {
+        state_dir.join(CERT_FILE).exists() && state_dir.join(KEY_FILE).exists()
+    }
+
+    /// Generates an identity that lives only in memory.
+    pub fn ephemeral(server_names: &[String], profile: &CertProfile) -> Result<Self> {
+        let (identity, _) = Self::generate(server_names, profile)?;
+        Ok(identity)
+    }
+
+    pub fn fingerprint(&self) -> CertFingerprint {
+        self.fingerprint
+    }
+
+    pub fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
+        self.cert_chain.clone()
+    }
+
+    pub fn key(&self) -> PrivateKeyDer<'static> {
+        self.key.clone_key()
+    }
+
+    /// The signature algorithm of the key, if rustls supports it.
+    pub fn key_algorithm(&self) -> Option<rustls::SignatureAlgorithm> {
+        rustls::crypto::aws_lc_rs::sign::any_supported_type(&self.key)
+            .ok()
+            .map(|key| key.algorithm())
+    }
+
+    /// The `cert-*` options `profile` sets that the stored certificate does
+    /// not match. Unset options and the default key type match anything.
+    pub fn profile_mismatches(&self, profile: &CertProfile) -> Vec<&'static str> {
+        let mut mismatches = Vec::new();
+        let Some(leaf) = self.cert_chain.first() else {
+            return mismatches;
+        };
+        let Ok((_, cert)) = x509_parser::parse_x509_certificate(leaf) else {
+            return mismatches;
+        };
+
+        let key_type = match self.key_algorithm() {
+            Some(rustls::SignatureAlgorithm::ECDSA) => Some(KeyType::P256),
+            Some(rustls::SignatureAlgorithm::ED25519) => Some(KeyType::Ed25519),
+            Some(rustls::SignatureAlgorithm::RSA) => Some(KeyType::Rsa2048),
+            _ => None,
+        };
+        if profile.key_type != KeyType::default() && key_type != Some(profile.key_type) {
+            mismatches.push("cert-key");
+        }
+
+        if let Some(issuer) = &profile.issuer {
+            let organization = cert.issuer().iter_organization().next()
+                .and_then(|organization| organization.as_str().ok());
+            if organization != Some(issuer.as_str()) {
+                mismatches.push("cert-issuer");
+            }
+        }
+
+        if let Some(days) = profile.validity_days {
+            let validity = cert.validity();
+            let lifetime = validity.not_after.to_datetime() - validity.not_before.to_datetime();
+            if lifetime.whole_days() != i64::from(days) {
+                mismatches.push("cert-validity");
+            }
+        }
+
+        if profile.chain && self.cert_chain.len() < 2 {
+            mismatches.push("cert-chain");
+        }
+
+        mismatches
+    }
+
+    /// The DNS names the certificate is for, leaving out wildcards.
+    pub fn server_names(&self) -> Vec<String> {
+        let Some(cert) = self.cert_chain.first()
+            .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+        else {
+            return Vec::new();
+        };
+
+        cert.valid_dns_names()
+            .filter(|name| !name.starts_with('*'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// A certificate chain for `server_name`: the stored one if it covers the
+    /// name or was supplied by the operator, else a new one for the same key
+    /// made according to `profile`, which keeps the fingerprint unchanged.
+    pub fn cert_chain_for(&self, server_name: &str, profile: &CertProfile) -> Result<Vec<CertificateDer<'static>>> {
+        let name = rustls::pki_types::ServerName::try_from(server_name)
+            .context(format!("Invalid server name: {}", server_name))?;
+        let covered = self.cert_chain.first()
+            .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+            .is_some_and(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok());
+        if covered || self.provided {
+            return Ok(self.cert_chain.clone());
+        }
+
+        let key_pair = rcgen::KeyPair::try_from(&self.key)
+            .context("Failed to load server key for certificate generation")?;
+        profile.issue(&key_pair, &[server_name.to_string()])
+            .context(format!("Failed to generate certificate for {}", server_name))
+    }
+
+    fn create(state_dir: &Path, server_names: &[String], profile: &CertProfile) -> Result<Self> {
+        create_state_dir(state_dir)?;
+
+        let (identity, key_pair) = Self::generate(server_names, profile)?;
+
+        let cert_pem: String = identity.cert_chain.iter()
+            .map(|cert| pem_encode("CERTIFICATE", cert))
+            .collect();
+        write_private_file(&state_dir.join(KEY_FILE), &key_pair.serialize_pem())?;
+        write_private_file(&state_dir.join(CERT_FILE), &cert_pem)?;
+
+        tracing::info!("Created new server identity in {}", state_dir.display());
+
+        Ok(identity)
+    }
+
+    fn generate(server_names: &[String], profile: &CertProfile) -> Result<(Self, rcgen::KeyPair)> {
+        let key_pair = profile.generate_key()?;
+        let cert_chain = profile.issue(&key_pair, server_names)?;
+
+        let key = PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
+        let fingerprint = CertFingerprint::from_certificate(&cert_chain[0])?;
+
+        let identity = ServerIdentity {
+            cert_chain,
+            key,
+            fingerprint,
+            provided: false,
+        };
+
+        Ok((identity, key_pair))
+    }
+
+    fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        check_permissions(key_path, 0o077)?;
+        check_permissions(cert_path, 0o022)?;
+
+        let cert_chain = CertificateDer::pem_file_iter(cert_path)
+            .context(format!("Failed to open {}", cert_path.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .context(format!("Failed to parse {}", cert_path.display()))?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .context(format!("Failed to parse {}", key_path.display()))?;
+
+        let end_entity = cert_chain.first()
+            .context(format!("No certificate found in {}", cert_path.display()))?;
+        let fingerprint = CertFingerprint::from_certificate(end_entity)?;
+
+        Ok(ServerIdentity {
+            cert_chain,
+            key,
+            fingerprint,
+            provided: false,
+        })
+    }
+}
+
+fn pem_encode(tag: &str, der: &[u8]) -> String {
+    pem::encode(&pem::Pem::new(tag, der.to_vec()))
+}
+
+/// Where to move `path` before replacing it: `<path>.<UTC time>.old`, with a
+/// counter added if needed, so that every earlier version is kept.
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let now = time::OffsetDateTime::now_utc();
+    let stamp = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+
+    (0u32..)
+        .map(|n| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", stamp));
+            if n > 0 {
+                name.push(format!("-{}", n));
+            }
+            name.push(".old");
+            PathBuf::from(name)
+        })
+        .find(|backup| !backup.exists())
+        .expect("ran out of backup names")
+}
+
+pub(crate) fn create_state_dir(state_dir: &Path) -> Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    builder.create(state_dir)
+        .context(format!("Failed to create state directory {}", state_dir.display()))
+}
+
+/// Writes `contents` to `path` with owner-only permissions, replacing it atomically.
+pub(crate) fn write_private_file(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+
+    let tmp_path = path.with_extension("tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)
+        .context(format!("Failed to create {}", tmp_path.display()))?;
+    file.write_all(contents.as_bytes())
+        .context(format!("Failed to write {}", tmp_path.display()))?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)
+        .context(format!("Failed to move {} into place", path.display()))
+}
+
+/// Refuses files whose mode has any of the `forbidden` permission bits set.
+#[cfg(unix)]
+pub(crate) fn check_permissions(path: &Path, forbidden: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .context(format!("Failed to stat {}", path.display()))?
+        .permissions()
+        .mode();
+
+    if mode & forbidden != 0 {
+        anyhow::bail!(
+            "Refusing to use {}: permissions {:o} are too open",
+            path.display(),
+            mode & 0o777
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_permissions(_path: &Path, _forbidden: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_every_previous_identity() {
+        let state_dir = std::env::temp_dir().join(format!("quictor-identity-test-{}", std::process::id()));
+        let names = vec!["example.com".to_string()];
+        let profile = CertProfile::default();
+
+        let first = ServerIdentity::load_or_create(&state_dir, &names, &profile).unwrap();
+        let second = ServerIdentity::rotate(&state_dir, &names, &profile).unwrap();
+        let third = ServerIdentity::rotate(&state_dir, &names, &profile).unwrap();
+        assert_ne!(first.fingerprint(), second.fingerprint());
+        assert_ne!(second.fingerprint(), third.fingerprint());
+
+        let backups: Vec<_> = std::fs::read_dir(&state_dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(KEY_FILE) && name.ends_with(".old"))
+            .collect();
+        std::fs::remove_dir_all(&state_dir).unwrap();
+        assert_eq!(backups.len(), 2, "{:?}", backups);
+    }
+
+    #[test]
+    fn reports_options_the_stored_certificate_does_not_match() {
+        let names = vec!["example.com".to_string()];
+        let profile = CertProfile {
+            key_type: KeyType::default(),
+            issuer: Some("Example Trust".to_string()),
+            validity_days: Some(30),
+            chain: false,
+        };
+        let identity = ServerIdentity::ephemeral(&names, &profile).unwrap();
+        assert!(identity.profile_mismatches(&profile).is_empty());
+        assert!(identity.profile_mismatches(&CertProfile::default()).is_empty());
+
+        let other = CertProfile {
+            key_type: KeyType::Ed25519,
+            issuer: Some("Other Trust".to_string()),
+            validity_days: Some(90),
+            chain: true,
+        };
+        assert_eq!(
+            identity.profile_mismatches(&other),
+            vec!["cert-key", "cert-issuer", "cert-validity", "cert-chain"]
+        );
+    }
+}
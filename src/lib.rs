@@ -1,6 +1,14 @@
+pub mod acme;
+pub mod auth;
+pub mod certificate;
+pub mod commands;
 pub mod config;
+pub mod identity;
+pub mod probe;
 pub mod pt;
+pub mod shutdown;
 pub mod socks5;
+pub mod standalone;
 
 pub use config::{configure_client, configure_server};
 
@@ -1,43 +1,128 @@
-mod pt;
-mod socks5;
-mod config;
-
 use anyhow::Result;
+use clap::{Parser, Subcommand};
+use quictor_pt::commands::{self, BridgeLineFlags, KeygenFlags};
+use quictor_pt::probe::{self, ProbeFlags};
+use quictor_pt::pt;
+use quictor_pt::shutdown::Shutdown;
+use quictor_pt::standalone::{ClientFlags, ServerFlags};
 use tracing::{info, error};
 
+/// QUIC pluggable transport for Tor.
+///
+/// Without a subcommand it runs in managed mode, configured by the TOR_PT_*
+/// environment variables that Tor sets when it launches the transport.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a client with a fixed SOCKS5 port, without Tor managing the process
+    Client(ClientFlags),
+    /// Run a server that forwards to a fixed address, without Tor managing the process
+    Server(ServerFlags),
+    /// Create the persistent server identity and print its fingerprint
+    Keygen(KeygenFlags),
+    /// Print a Bridge line for the server identity in a state directory
+    BridgeLine(BridgeLineFlags),
+    /// Validate the TOR_PT_* environment, or a standalone configuration, without binding anything
+    Check {
+        #[command(subcommand)]
+        target: Option<CheckTarget>,
+    },
+    /// Test a bridge line: DNS, QUIC handshake, certificate and ORPort reachability
+    Probe(ProbeFlags),
+}
+
+#[derive(Subcommand)]
+enum CheckTarget {
+    /// Validate a standalone client configuration
+    Client(ClientFlags),
+    /// Validate a standalone server configuration
+    Server(ServerFlags),
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
 
-    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // stdout carries the PT protocol, so diagnostics must stay on stderr.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
-    info!("QuicTor Pluggable Transport starting...");
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
-    let mode = match pt::detect_mode() {
-        Ok(mode) => mode,
-        Err(e) => {
-            error!("Failed to detect PT mode: {}", e);
-            return Err(e);
+    let result = match cli.command {
+        Some(Command::Keygen(flags)) => return commands::keygen(flags),
+        Some(Command::BridgeLine(flags)) => return commands::bridge_line(flags),
+        Some(Command::Probe(flags)) => return probe::probe(flags).await,
+        Some(Command::Check { target }) => {
+            return match target {
+                None => commands::check_managed(),
+                Some(CheckTarget::Client(flags)) => commands::check_client(flags.into_env()),
+                Some(CheckTarget::Server(flags)) => commands::check_server(flags.into_env()),
+            };
+        }
+        None => {
+            info!("QuicTor Pluggable Transport starting...");
+            run_managed().await
+        }
+        Some(Command::Client(flags)) => {
+            info!("Starting standalone client...");
+            match flags.into_env() {
+                Ok(env) => pt::client::run_client_with_env(env, start_shutdown()).await,
+                Err(e) => Err(e.context("Invalid client configuration")),
+            }
+        }
+        Some(Command::Server(flags)) => {
+            info!("Starting standalone server...");
+            match flags.into_env() {
+                Ok(env) => pt::server::run_server_with_env(env, start_shutdown()).await,
+                Err(e) => Err(e.context("Invalid server configuration")),
+            }
         }
     };
 
+    if let Err(e) = result {
+        error!("PT execution failed: {:#}", e);
+        return Err(e);
+    }
+
+    info!("QuicTor Pluggable Transport stopped");
+
+    Ok(())
+}
+
+/// Runs as a Tor-managed transport.
+async fn run_managed() -> Result<()> {
+    use anyhow::Context;
+
+    let mode = pt::detect_mode()
+        .context("Failed to detect PT mode (use `client` or `server` to run without Tor)")?;
+
     info!("Running in {:?} mode", mode);
 
-    let result = match mode {
+    pt::negotiate_version()
+        .context("PT version negotiation failed")?;
+
+    match mode {
         pt::PtMode::Client => {
             info!("Starting PT Client...");
-            pt::client::run_client().await
+            pt::client::run_client(start_shutdown()).await
         }
         pt::PtMode::Server => {
             info!("Starting PT Server...");
-            pt::server::run_server().await
+            pt::server::run_server(start_shutdown()).await
         }
-    };
-
-    if let Err(e) = result {
-        error!("PT execution failed: {}", e);
-        return Err(e);
     }
+}
 
-    Ok(())
+fn start_shutdown() -> Shutdown {
+    let shutdown = Shutdown::new();
+    shutdown.watch_signals();
+    shutdown
 }
@@ -0,0 +1,293 @@
+use crate::config::CertFingerprint;
+use crate::pt::bridge::{BridgeLine, BridgeParams};
+use crate::pt::client::{is_certificate_rejection, resolve_bridge_address};
+use crate::pt::header::{StreamHeader, StreamKind, StreamStatus};
+use crate::pt::transport::Transport;
+use crate::shutdown::{SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON};
+use quinn::Endpoint;
+use rustls::pki_types::{CertificateDer, ServerName};
+use serde::Serialize;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Tor cell command for VERSIONS, the first cell on every link.
+const CELL_VERSIONS: u8 = 7;
+/// Link protocol versions we offer; any relay answers with its own list.
+const LINK_VERSIONS: &[u16] = &[3, 4, 5];
+
+/// Flags for `quictor-pt probe`.
+#[derive(Debug, Clone, clap::Args)]
+pub struct ProbeFlags {
+    /// Bridge line to test, e.g. "quictor 203.0.113.5:443 cert=... sni=..."
+    pub bridge_line: String,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Seconds to wait for each step
+    #[arg(long, default_value_t = 10)]
+    pub timeout: u64,
+}
+
+/// What a probe found out about a bridge. Steps after the first failure are
+/// left empty.
+#[derive(Debug, Default, Serialize)]
+pub struct ProbeReport {
+    pub bridge: String,
+    pub transport: String,
+    pub resolved_address: Option<SocketAddr>,
+    pub dns_ms: Option<f64>,
+    pub handshake_ms: Option<f64>,
+    pub rtt_ms: Option<f64>,
+    pub alpn: Option<String>,
+    pub tls_version: Option<String>,
+    pub fingerprint_match: Option<bool>,
+    pub server_fingerprint: Option<String>,
+    pub orport_reachable: Option<bool>,
+    pub link_versions: Vec<u16>,
+    pub error: Option<String>,
+}
+
+impl ProbeReport {
+    fn print_text(&self) {
+        fn or_dash<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_else(|| "-".to_string())
+        }
+        fn millis(value: Option<f64>) -> String {
+            value.map(|ms| format!("{:.1} ms", ms)).unwrap_or_else(|| "-".to_string())
+        }
+
+        println!("bridge:             {} ({})", self.bridge, self.transport);
+        println!("resolved address:   {}", or_dash(&self.resolved_address));
+        println!("dns:                {}", millis(self.dns_ms));
+        println!("quic handshake:     {}", millis(self.handshake_ms));
+        println!("rtt:                {}", millis(self.rtt_ms));
+        println!("alpn:               {}", or_dash(&self.alpn));
+        println!("tls version:        {}", or_dash(&self.tls_version));
+        println!("fingerprint match:  {}", or_dash(&self.fingerprint_match));
+        println!("server fingerprint: {}", or_dash(&self.server_fingerprint));
+        println!("orport reachable:   {}", or_dash(&self.orport_reachable));
+        if !self.link_versions.is_empty() {
+            let versions: Vec<String> = self.link_versions.iter().map(u16::to_string).collect();
+            println!("link versions:      {}", versions.join(", "));
+        }
+        if let Some(error) = &self.error {
+            println!("error:              {}", error);
+        }
+    }
+}
+
+/// Tests one bridge end to end and prints what it found.
+pub async fn probe(flags: ProbeFlags) -> anyhow::Result<()> {
+    let mut report = ProbeReport::default();
+    let step_timeout = Duration::from_secs(flags.timeout);
+
+    if let Err(e) = run_probe(&flags.bridge_line, step_timeout, &mut report).await {
+        report.error = Some(format!("{:#}", e));
+    }
+
+    if flags.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print_text();
+    }
+
+    match report.error {
+        Some(_) => anyhow::bail!("Bridge probe failed"),
+        None => Ok(()),
+    }
+}
+
+async fn run_probe(
+    bridge_line: &str,
+    step_timeout: Duration,
+    report: &mut ProbeReport,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::time::timeout;
+
+    let line: BridgeLine = bridge_line.parse()?;
+    report.bridge = line.address.clone();
+    report.transport = line.transport.clone();
+
+    let transport = Transport::lookup(&line.transport)
+        .context(format!("No such transport: {}", line.transport))?;
+    let params = BridgeParams::from_args(&line.args, transport)?;
+
+    let started = Instant::now();
+    let mut bridge_addr = timeout(step_timeout, resolve_bridge_address(&line.address))
+        .await
+        .context("Timed out resolving the bridge address")??;
+    report.dns_ms = Some(elapsed_ms(started));
+    if let Some(port) = params.port {
+        bridge_addr.set_port(port);
+    }
+    report.resolved_address = Some(bridge_addr);
+
+    let local_addr = match bridge_addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let endpoint = Endpoint::client(local_addr)
+        .context("Failed to create QUIC endpoint")?;
+
+    // A fresh ticket store, so the probe always measures a full handshake.
+    let session_store = Arc::new(rustls::client::ClientSessionMemoryCache::new(1));
+    let client_config = crate::config::configure_client(&params, session_store)?;
+    let started = Instant::now();
+    let connecting = endpoint.connect_with(client_config, bridge_addr, params.connect_name())?;
+    let connection = match timeout(step_timeout, connecting).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            if is_certificate_rejection(&e) {
+                report.fingerprint_match = Some(false);
+            }
+            return Err(anyhow::Error::from(e).context("QUIC handshake failed"));
+        }
+        Err(_) => anyhow::bail!("No reply to the QUIC handshake within {:?} (is UDP blocked?)", step_timeout),
+    };
+    report.handshake_ms = Some(elapsed_ms(started));
+    report.rtt_ms = Some(connection.rtt().as_secs_f64() * 1000.0);
+
+    if let Some(handshake) = connection.handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+    {
+        report.alpn = handshake.protocol.map(|p| String::from_utf8_lossy(&p).into_owned());
+    }
+    // quinn's rustls integration only speaks TLS 1.3, as QUIC requires.
+    report.tls_version = Some("TLSv1.3".to_string());
+
+    if let Some(certs) = connection.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+    {
+        if let Some(end_entity) = certs.first() {
+            let fingerprint = CertFingerprint::from_certificate(end_entity)?;
+            report.fingerprint_match = Some(fingerprint == params.fingerprint);
+            report.server_fingerprint = Some(fingerprint.to_string());
+        }
+    }
+
+    report.orport_reachable = Some(false);
+    let versions = timeout(step_timeout, exchange_versions(&connection, bridge_addr, &params))
+        .await
+        .context("Timed out waiting for the ORPort's VERSIONS cell")?
+        .context("Stream did not reach the ORPort")?;
+    report.orport_reachable = Some(true);
+    report.link_versions = versions;
+
+    connection.close(SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON);
+    endpoint.wait_idle().await;
+
+    Ok(())
+}
+
+/// Opens a stream, starts a Tor link handshake with the relay behind the
+/// bridge and returns the link protocol versions from its VERSIONS cell.
+async fn exchange_versions(
+    connection: &quinn::Connection,
+    bridge_addr: SocketAddr,
+    params: &BridgeParams,
+) -> anyhow::Result<Vec<u16>> {
+    use anyhow::Context;
+
+    let (mut send, mut recv) = connection.open_bi()
+        .await
+        .context("Failed to open stream")?;
+
+    let mut header = StreamHeader::new(StreamKind::Relay);
+    header.token = params.auth.map(|auth| auth.token()).transpose()?;
+    header.write(&mut send, params.padding).await?;
+    match StreamStatus::read(&mut recv).await? {
+        StreamStatus::Accepted => {}
+        status => anyhow::bail!("Bridge refused the stream: {}", status),
+    }
+    let stream = tokio::io::join(recv, send);
+
+    // Tor authenticates relays inside the link protocol, not through TLS, so
+    // any certificate is fine here. An IP server name keeps SNI out of the
+    // ClientHello, as Tor clients do.
+    let tls_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCertificate::new()))
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+    let mut tls = connector.connect(ServerName::IpAddress(bridge_addr.ip().into()), stream)
+        .await
+        .context("TLS handshake with the ORPort failed")?;
+
+    // Variable-length cell with a 2-byte circuit ID, as VERSIONS always uses.
+    let mut cell = vec![0x00, 0x00, CELL_VERSIONS];
+    cell.extend_from_slice(&((LINK_VERSIONS.len() * 2) as u16).to_be_bytes());
+    for version in LINK_VERSIONS {
+        cell.extend_from_slice(&version.to_be_bytes());
+    }
+    tls.write_all(&cell).await?;
+    tls.flush().await?;
+
+    let mut header = [0u8; 5];
+    tls.read_exact(&mut header).await
+        .context("Failed to read the relay's first cell")?;
+    if header[2] != CELL_VERSIONS {
+        anyhow::bail!("Expected a VERSIONS cell, got command {}", header[2]);
+    }
+
+    let mut payload = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+    tls.read_exact(&mut payload).await
+        .context("Failed to read the VERSIONS cell")?;
+
+    Ok(payload.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect())
+}
+
+fn elapsed_ms(since: Instant) -> f64 {
+    since.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Accepts any certificate but still checks handshake signatures.
+#[derive(Debug)]
+struct AnyServerCertificate {
+    algorithms: rustls::crypto::WebPkiSupportedAlgorithms,
+}
+
+impl AnyServerCertificate {
+    fn new() -> Self {
+        AnyServerCertificate {
+            algorithms: rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for AnyServerCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
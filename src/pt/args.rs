@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// `key=value` transport arguments as exchanged with Tor.
+///
+/// Client-side they arrive in the SOCKS5 username/password fields, encoded as
+/// `k=v;k=v` with `\` escaping `;`, `=` and `\` inside keys and values.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PtArgs {
+    args: BTreeMap<String, String>,
+}
+
+impl PtArgs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.args.insert(key.into(), value.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.args.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Parses the `k=v;k=v` form Tor uses for SOCKS5 per-bridge arguments.
+    /// A repeated key keeps its last value.
+    pub fn parse_socks(s: &str) -> anyhow::Result<Self> {
+        let mut args = PtArgs::new();
+
+        for pair in split_escaped(s, ';')? {
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, value) = split_key_value(&pair)?;
+            args.insert(key, value);
+        }
+
+        Ok(args)
+    }
+
+    /// Formats the arguments for an `SMETHOD ... ARGS:` line, escaping `,`, `=` and `\`.
+    pub fn to_smethod_args(&self) -> String {
+        self.iter()
+            .map(|(k, v)| format!("{}={}", escape(k, ",=\\"), escape(v, ",=\\")))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Parses `TOR_PT_SERVER_TRANSPORT_OPTIONS`: `transport:k=v;transport:k=v`, where
+/// `\` escapes `:`, `;`, `=` and `\`. Options are grouped by transport name.
+pub fn parse_transport_options(s: &str) -> anyhow::Result<HashMap<String, PtArgs>> {
+    let mut options: HashMap<String, PtArgs> = HashMap::new();
+
+    for item in split_escaped(s, ';')? {
+        if item.is_empty() {
+            continue;
+        }
+
+        let parts = split_escaped(&item, ':')?;
+        if parts.len() < 2 {
+            anyhow::bail!("Transport option is missing 'transport:' prefix: {}", item);
+        }
+        let transport = unescape(&parts[0]);
+        if transport.is_empty() {
+            anyhow::bail!("Transport option has an empty transport name: {}", item);
+        }
+
+        let (key, value) = split_key_value(&parts[1..].join(":"))?;
+        options.entry(transport).or_default().insert(key, value);
+    }
+
+    Ok(options)
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn escape(s: &str, special: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Splits `s` at unescaped `sep`, keeping escape sequences intact for the next stage.
+fn split_escaped(s: &str, sep: char) -> anyhow::Result<Vec<String>> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escaped = chars.next()
+                .ok_or_else(|| anyhow::anyhow!("Trailing backslash in arguments: {}", s))?;
+            current.push('\\');
+            current.push(escaped);
+        } else if c == sep {
+            parts.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+
+    Ok(parts)
+}
+
+/// Splits an escaped `key=value` pair at its first unescaped `=` and unescapes both halves.
+fn split_key_value(pair: &str) -> anyhow::Result<(String, String)> {
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut chars = pair.chars();
+
+    while let Some(c) = chars.next() {
+        let (c, escaped) = if c == '\\' {
+            // split_escaped guarantees a character follows every backslash.
+            (chars.next().unwrap_or('\\'), true)
+        } else {
+            (c, false)
+        };
+
+        if c == '=' && !escaped && !in_value {
+            in_value = true;
+        } else if in_value {
+            value.push(c);
+        } else {
+            key.push(c);
+        }
+    }
+
+    if !in_value {
+        anyhow::bail!("Argument is missing '=': {}", pair);
+    }
+    if key.is_empty() {
+        anyhow::bail!("Argument has an empty key: {}", pair);
+    }
+
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escaped_socks_args() {
+        let args = PtArgs::parse_socks(r"a\;b=c\=d;e\\=f\\;;h=").unwrap();
+
+        assert_eq!(args.iter().collect::<Vec<_>>(), vec![("a;b", "c=d"), ("e\\", "f\\"), ("h", "")]);
+    }
+
+    #[test]
+    fn keeps_the_last_duplicate_key() {
+        let args = PtArgs::parse_socks("cert=A;sni=x;cert=B").unwrap();
+
+        assert_eq!(args.get("cert"), Some("B"));
+        assert_eq!(args.get("sni"), Some("x"));
+    }
+
+    #[test]
+    fn rejects_bad_socks_args() {
+        assert!(PtArgs::parse_socks("cert").is_err());
+        assert!(PtArgs::parse_socks("=value").is_err());
+        assert!(PtArgs::parse_socks(r"cert\=x").is_err());
+        assert!(PtArgs::parse_socks(r"cert=x\").is_err());
+        assert!(PtArgs::parse_socks("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn escapes_smethod_args() {
+        let args = PtArgs::parse_socks(r"alpn=h3,h3-29;x\=y=a\\b").unwrap();
+
+        assert_eq!(args.to_smethod_args(), r"alpn=h3\,h3-29,x\=y=a\\b");
+    }
+
+    #[test]
+    fn parses_transport_options() {
+        let options = parse_transport_options(
+            r"quictor_h3:sni=a.example;quictor_h3:decoy=C\:\\www;quictor:alpn=;quictor_h3:sni=b.example",
+        ).unwrap();
+
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["quictor_h3"].get("sni"), Some("b.example"));
+        assert_eq!(options["quictor_h3"].get("decoy"), Some(r"C:\www"));
+        assert_eq!(options["quictor"].get("alpn"), Some(""));
+    }
+
+    #[test]
+    fn rejects_bad_transport_options() {
+        assert!(parse_transport_options("sni=a.example").is_err());
+        assert!(parse_transport_options(":sni=a.example").is_err());
+        assert!(parse_transport_options("quictor_h3:sni").is_err());
+        assert!(parse_transport_options(r"quictor_h3:sni=a\").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use super::args::PtArgs;
+use super::transport::Transport;
+use crate::auth::AuthSecret;
+use crate::config::{CertFingerprint, PaddingProfile, ServerOptions};
+use crate::identity::ServerIdentity;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// `sni=` value that tells the client to send no server name at all.
+pub const NO_SERVER_NAME: &str = "none";
+
+/// Per-bridge client settings, taken from the arguments of a `Bridge quictor ...` line.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BridgeParams {
+    /// Pinned fingerprint of the bridge's certificate key (`cert=`).
+    pub fingerprint: CertFingerprint,
+    /// TLS server name to send (`sni=`), or `None` to send none.
+    pub server_name: Option<String>,
+    /// UDP port to dial instead of the one in the bridge address (`port=`).
+    pub port: Option<u16>,
+    /// ALPN protocols to offer, in preference order (`alpn=`, comma-separated).
+    pub alpn: Vec<String>,
+    /// Padding to add to stream headers (`padding=`).
+    pub padding: PaddingProfile,
+    /// Secret the bridge wants proof of on every stream (`auth=`).
+    pub auth: Option<AuthSecret>,
+}
+
+impl BridgeParams {
+    /// Reads the bridge line arguments, falling back to `transport`'s defaults.
+    pub fn from_args(args: &PtArgs, transport: &Transport) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let fingerprint = args.get("cert")
+            .context("Bridge line is missing the cert= argument")?
+            .parse()
+            .context("Invalid cert= argument")?;
+
+        let server_name = match args.get("sni") {
+            Some(NO_SERVER_NAME) => None,
+            Some(name) => Some(name.to_string()),
+            None => Some(DEFAULT_SERVER_NAME.to_string()),
+        };
+
+        let port = args.get("port")
+            .map(|p| p.parse::<u16>().context(format!("Invalid port= argument: {}", p)))
+            .transpose()?;
+
+        let alpn = args.get("alpn")
+            .map(|list| {
+                list.split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect()
+            })
+            .unwrap_or_else(|| transport.default_alpn());
+
+        let padding = args.get("padding")
+            .map(|p| p.parse().context("Invalid padding= argument"))
+            .transpose()?
+            .unwrap_or_default();
+
+        let auth = args.get("auth")
+            .map(|a| a.parse().context("Invalid auth= argument"))
+            .transpose()?;
+        if auth.is_none() {
+            tracing::warn!("Bridge line has no auth= argument; the bridge will likely refuse us");
+        }
+
+        for (key, _) in args.iter() {
+            if !matches!(key, "cert" | "sni" | "port" | "alpn" | "padding" | "auth") {
+                tracing::warn!("Ignoring unknown bridge argument: {}", key);
+            }
+        }
+
+        Ok(BridgeParams {
+            fingerprint,
+            server_name,
+            port,
+            alpn,
+            padding,
+            auth,
+        })
+    }
+
+    /// The parameters clients need to reach a server with `identity` and `options`.
+    pub fn for_server(identity: &ServerIdentity, auth: AuthSecret, options: &ServerOptions) -> Self {
+        BridgeParams {
+            fingerprint: identity.fingerprint(),
+            server_name: options.names_for(identity).into_iter().next(),
+            port: None,
+            alpn: options.alpn.clone(),
+            padding: options.padding,
+            auth: Some(auth),
+        }
+    }
+
+    /// The name to connect with. Without SNI it is never sent, but quinn and
+    /// rustls still want one, for instance to file session tickets under.
+    pub fn connect_name(&self) -> &str {
+        self.server_name.as_deref().unwrap_or(DEFAULT_SERVER_NAME)
+    }
+
+    /// The inverse of [`BridgeParams::from_args`], used by the server to advertise itself.
+    pub fn to_args(&self) -> PtArgs {
+        let mut args = PtArgs::new();
+
+        args.insert("cert", self.fingerprint.to_string());
+        args.insert("sni", self.server_name.as_deref().unwrap_or(NO_SERVER_NAME));
+        if let Some(port) = self.port {
+            args.insert("port", port.to_string());
+        }
+        // Always given, as an empty list would otherwise read back as the
+        // transport's default.
+        args.insert("alpn", self.alpn.join(","));
+        if self.padding != PaddingProfile::None {
+            args.insert("padding", self.padding.as_str());
+        }
+        if let Some(auth) = self.auth {
+            args.insert("auth", auth.to_string());
+        }
+
+        args
+    }
+}
+
+/// A torrc `Bridge <transport> <address> [fingerprint] [key=value ...]` line.
+/// The leading `Bridge` keyword is optional when parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BridgeLine {
+    pub transport: String,
+    /// `host:port`, which may name a host rather than an IP address.
+    pub address: String,
+    /// The relay's 40-hex-digit Tor identity fingerprint.
+    pub relay_fingerprint: Option<String>,
+    pub args: PtArgs,
+}
+
+impl FromStr for BridgeLine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let mut words = s.split_whitespace().peekable();
+        if words.peek().is_some_and(|w| w.eq_ignore_ascii_case("bridge")) {
+            words.next();
+        }
+
+        let transport = words.next()
+            .context("Bridge line is missing the transport name")?
+            .to_string();
+        let address = words.next()
+            .context("Bridge line is missing the address")?
+            .to_string();
+
+        let mut relay_fingerprint = None;
+        let mut args = PtArgs::new();
+        for word in words {
+            match word.split_once('=') {
+                Some((key, value)) => args.insert(key, value),
+                None if relay_fingerprint.is_none()
+                    && args.is_empty()
+                    && word.len() == 40
+                    && word.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    relay_fingerprint = Some(word.to_string());
+                }
+                None => anyhow::bail!("Unexpected word in bridge line: {}", word),
+            }
+        }
+
+        Ok(BridgeLine {
+            transport,
+            address,
+            relay_fingerprint,
+            args,
+        })
+    }
+}
+
+impl fmt::Display for BridgeLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bridge {} {}", self.transport, self.address)?;
+        if let Some(fingerprint) = &self.relay_fingerprint {
+            write!(f, " {}", fingerprint)?;
+        }
+        for (key, value) in self.args.iter() {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = "0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF";
+    const AUTH: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    fn quictor_h3() -> &'static Transport {
+        Transport::lookup("quictor_h3").unwrap()
+    }
+
+    #[test]
+    fn reads_bridge_arguments_with_defaults() {
+        let args = PtArgs::parse_socks(&format!("cert={};auth={}", CERT, AUTH)).unwrap();
+        let params = BridgeParams::from_args(&args, quictor_h3()).unwrap();
+
+        assert_eq!(params.fingerprint.to_string(), CERT);
+        assert_eq!(params.server_name.as_deref(), Some(DEFAULT_SERVER_NAME));
+        assert_eq!(params.port, None);
+        assert_eq!(params.alpn, vec!["h3".to_string()]);
+        assert_eq!(params.padding, PaddingProfile::None);
+        assert_eq!(params.auth.map(|auth| auth.to_string()).as_deref(), Some(AUTH));
+    }
+
+    #[test]
+    fn reads_empty_and_escaped_values() {
+        let args = PtArgs::parse_socks(&format!(r"cert={};sni=none;alpn=;x\;y=a\=b", CERT)).unwrap();
+        let params = BridgeParams::from_args(&args, quictor_h3()).unwrap();
+
+        assert_eq!(params.server_name, None);
+        assert!(params.alpn.is_empty());
+        assert_eq!(params.auth, None);
+        assert_eq!(args.get("x;y"), Some("a=b"));
+    }
+
+    #[test]
+    fn rejects_bad_bridge_arguments() {
+        let transport = quictor_h3();
+        let parse = |s: &str| BridgeParams::from_args(&PtArgs::parse_socks(s).unwrap(), transport);
+
+        assert!(parse("sni=example.com").is_err());
+        assert!(parse("cert=").is_err());
+        assert!(parse("cert=0123").is_err());
+        assert!(parse(&format!("cert={};port=", CERT)).is_err());
+        assert!(parse(&format!("cert={};port=65536", CERT)).is_err());
+        assert!(parse(&format!("cert={};padding=lots", CERT)).is_err());
+        assert!(parse(&format!("cert={};auth=", CERT)).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_to_args() {
+        let transport = quictor_h3();
+        let mut params = BridgeParams::from_args(&PtArgs::parse_socks(&format!(
+            "cert={};sni=cdn.example.com;port=8443;alpn=h3,h3-29;padding=heavy;auth={}",
+            CERT, AUTH
+        )).unwrap(), transport).unwrap();
+        assert_eq!(BridgeParams::from_args(&params.to_args(), transport).unwrap(), params);
+
+        params.server_name = None;
+        params.port = None;
+        params.alpn.clear();
+        params.padding = PaddingProfile::None;
+        params.auth = None;
+        assert_eq!(BridgeParams::from_args(&params.to_args(), transport).unwrap(), params);
+    }
+
+    #[test]
+    fn round_trips_bridge_lines() {
+        let line = format!("Bridge quictor_h3 203.0.113.5:443 {} alpn=h3 cert={} sni=none", "A".repeat(40), CERT);
+        let parsed: BridgeLine = line.parse().unwrap();
+
+        assert_eq!(parsed.relay_fingerprint, Some("A".repeat(40)));
+        assert_eq!(parsed.args.get("sni"), Some("none"));
+        assert_eq!(parsed.to_string(), line);
+    }
+}
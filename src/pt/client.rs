@@ -1,143 +1,591 @@
-use super::env::ClientEnv;
+use super::bridge::BridgeParams;
+use super::env::{ClientEnv, OutboundBind, ProxyConfig, ProxyKind};
+use super::header::{StreamHeader, StreamKind, StreamStatus};
+use super::pool::{BridgeConnection, ConnectionPool};
+use super::session::SessionCache;
+use super::LogSeverity;
+use super::transport::{self, Transport};
+use crate::shutdown::{Shutdown, DRAIN_TIMEOUT, SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON};
+use crate::socks5::udp::Socks5UdpSocket;
 use crate::socks5::Socks5Server;
 use quinn::Endpoint;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Most of what Tor writes during a resumed handshake that is sent as 0-RTT
+/// data. Tor waits for the bridge's answer to its ClientHello, so this is
+/// rarely reached.
+const EARLY_DATA_LIMIT: usize = 16 * 1024;
+
+/// QUIC endpoints used to reach bridges, one per address family.
+///
+/// When an upstream proxy is in use both families share the proxied endpoint,
+/// since the proxy's relay can reach either.
+#[derive(Debug, Clone)]
+struct ClientEndpoints {
+    v4: Option<Endpoint>,
+    v6: Option<Endpoint>,
+}
+
+impl ClientEndpoints {
+    /// Binds an endpoint per address family on the `TOR_PT_OUTBOUND_BIND_ADDRESS_*`
+    /// addresses. A family that cannot be bound is skipped unless both fail.
+    fn bind(outbound_bind: &OutboundBind) -> anyhow::Result<Self> {
+        let v4_addr = SocketAddr::from((outbound_bind.v4.unwrap_or(Ipv4Addr::UNSPECIFIED), 0));
+        let v6_addr = SocketAddr::from((outbound_bind.v6.unwrap_or(Ipv6Addr::UNSPECIFIED), 0));
+
+        let v4 = Endpoint::client(v4_addr)
+            .map_err(|e| tracing::warn!("Failed to bind IPv4 QUIC endpoint on {}: {}", v4_addr, e))
+            .ok();
+        let v6 = Endpoint::client(v6_addr)
+            .map_err(|e| tracing::warn!("Failed to bind IPv6 QUIC endpoint on {}: {}", v6_addr, e))
+            .ok();
+
+        if v4.is_none() && v6.is_none() {
+            anyhow::bail!("Failed to create QUIC endpoint for either address family");
+        }
+
+        Ok(ClientEndpoints { v4, v6 })
+    }
+
+    fn proxied(endpoint: Endpoint) -> Self {
+        ClientEndpoints {
+            v4: Some(endpoint.clone()),
+            v6: Some(endpoint),
+        }
+    }
+
+    fn for_addr(&self, addr: SocketAddr) -> anyhow::Result<&Endpoint> {
+        use anyhow::Context;
+
+        match addr {
+            SocketAddr::V4(_) => self.v4.as_ref().context("No IPv4 QUIC endpoint available"),
+            SocketAddr::V6(_) => self.v6.as_ref().context("No IPv6 QUIC endpoint available"),
+        }
+    }
+
+    async fn close(&self) {
+        for endpoint in self.v4.iter().chain(&self.v6) {
+            endpoint.close(SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON);
+        }
+        for endpoint in self.v4.iter().chain(&self.v6) {
+            endpoint.wait_idle().await;
+        }
+    }
+}
+
+/// Runs the client with the configuration Tor passes in `TOR_PT_*` variables.
+pub async fn run_client(shutdown: Shutdown) -> anyhow::Result<()> {
+    use super::env_error;
+
+    let env = match ClientEnv::from_env() {
+        Ok(env) => env,
+        Err(e) => {
+            env_error(&format!("{:#}", e))?;
+            return Err(e.context("Failed to load client environment"));
+        }
+    };
+
+    run_client_with_env(env, shutdown).await
+}
 
-pub async fn run_client() -> anyhow::Result<()> {
+pub async fn run_client_with_env(env: ClientEnv, shutdown: Shutdown) -> anyhow::Result<()> {
     use anyhow::Context;
-    use super::{write_pt_message, PT_VERSION};
+    use super::{cmethod, cmethod_error, cmethods_done, proxy_done, proxy_error};
 
-    let env = ClientEnv::from_env()
-        .context("Failed to load client environment")?;
+    let endpoint_result = match &env.proxy {
+        Some(proxy) => match proxied_endpoint(proxy).await {
+            Ok(endpoint) => {
+                proxy_done()?;
+                Ok(ClientEndpoints::proxied(endpoint))
+            }
+            Err(e) => {
+                proxy_error(&format!("{:#}", e))?;
+                return Err(e.context("Failed to use TOR_PT_PROXY"));
+            }
+        },
+        None => ClientEndpoints::bind(&env.outbound_bind),
+    };
 
-    let client_config = crate::config::configure_client()
-        .context("Failed to configure QUIC client")?;
-    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)
-        .context("Failed to create QUIC endpoint")?;
-    endpoint.set_default_client_config(client_config);
+    let (transports, unknown) = transport::select(&env.transports);
+    for name in &unknown {
+        cmethod_error(name, "no such transport is supported")?;
+    }
 
-    let socks_server = Socks5Server::bind("127.0.0.1:0".parse()?)
-        .await
-        .context("Failed to bind SOCKS5 server")?;
+    let endpoints = match endpoint_result {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            for transport in &transports {
+                cmethod_error(transport.name, &format!("{:#}", e))?;
+            }
+            cmethods_done()?;
+            return Err(e);
+        }
+    };
 
-    let socks_addr = socks_server.local_addr()
-        .context("Failed to get SOCKS5 server address")?;
+    let pool = ConnectionPool::new();
+    let sessions = SessionCache::new();
 
-    write_pt_message(&format!("VERSION {}", PT_VERSION))?;
-    write_pt_message(&format!("CMETHOD quictor socks5 {}", socks_addr))?;
-    write_pt_message("CMETHODS DONE")?;
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for transport in transports {
+        let socks_server = match bind_socks_server(env.socks_addr).await {
+            Ok(server) => server,
+            Err(e) => {
+                cmethod_error(transport.name, &format!("{:#}", e))?;
+                continue;
+            }
+        };
+
+        cmethod(transport.name, socks_server.local_addr()?)?;
+        accept_loops.spawn(accept_socks_connections(
+            transport,
+            socks_server,
+            endpoints.clone(),
+            pool.clone(),
+            sessions.clone(),
+            shutdown.clone(),
+        ));
+    }
+    cmethods_done()?;
+
+    if accept_loops.is_empty() {
+        anyhow::bail!("None of the requested transports could be started");
+    }
 
+    while let Some(result) = accept_loops.join_next().await {
+        result.context("Accept loop panicked")?;
+    }
+
+    if !shutdown.drain(DRAIN_TIMEOUT).await {
+        tracing::warn!("Streams still open after {:?}, closing connections", DRAIN_TIMEOUT);
+    }
+
+    endpoints.close().await;
+
+    Ok(())
+}
+
+/// Creates a QUIC endpoint whose datagrams go through the upstream proxy.
+///
+/// QUIC needs UDP, so only SOCKS5 proxies (via UDP ASSOCIATE) can carry it.
+async fn proxied_endpoint(proxy: &str) -> anyhow::Result<Endpoint> {
+    use anyhow::Context;
+
+    let proxy: ProxyConfig = proxy.parse()?;
+    if proxy.kind != ProxyKind::Socks5 {
+        anyhow::bail!("Only socks5 proxies can relay QUIC traffic, got {:?}", proxy.kind);
+    }
+
+    let socket = Socks5UdpSocket::associate(proxy.addr, proxy.credentials.as_ref()).await?;
+
+    Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        None,
+        Arc::new(socket),
+        Arc::new(quinn::TokioRuntime),
+    )
+    .context("Failed to create QUIC endpoint")
+}
+
+async fn bind_socks_server(addr: Option<SocketAddr>) -> anyhow::Result<Socks5Server> {
+    use anyhow::Context;
+
+    let addr = addr.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    Socks5Server::bind(addr)
+        .await
+        .context(format!("Failed to bind SOCKS5 server on {}", addr))
+}
+
+async fn accept_socks_connections(
+    transport: &'static Transport,
+    socks_server: Socks5Server,
+    endpoints: ClientEndpoints,
+    pool: ConnectionPool,
+    sessions: SessionCache,
+    shutdown: Shutdown,
+) {
     loop {
-        let socks_conn = match socks_server.accept().await {
+        let accepted = tokio::select! {
+            accepted = socks_server.accept() => accepted,
+            _ = shutdown.triggered() => {
+                tracing::debug!("No longer accepting SOCKS5 connections for {}", transport.name);
+                break;
+            }
+        };
+        let socks_conn = match accepted {
             Ok(conn) => conn,
             Err(e) => {
-                tracing::error!("Failed to accept SOCKS5 connection: {}", e);
+                let message = format!("Failed to accept SOCKS5 connection: {:#}", e);
+                tracing::error!("{}", message);
+                if let Err(e) = super::log(LogSeverity::Warning, &format!("{}: {}", transport.name, message)) {
+                    tracing::warn!("Failed to send LOG to Tor: {}", e);
+                }
                 continue;
             }
         };
 
-        let target_addr = socks_conn.target_addr();
-        let socks_stream = socks_conn.into_stream();
-
-        let quic_server_addr_str = std::env::var("QUIC_SERVER_ADDR")
-            .unwrap_or_else(|_| "127.0.0.1:4433".to_string());
+        // Bad bridge arguments get a SOCKS failure rather than a connection
+        // that is closed right after it was reported as established.
+        let bridge_addr_str = socks_conn.target().to_string();
+        let params = match BridgeParams::from_args(socks_conn.args(), transport) {
+            Ok(params) => params,
+            Err(e) => {
+                let message = format!("Invalid arguments for bridge {}: {:#}", bridge_addr_str, e);
+                tracing::error!("{}", message);
+                report_connect_failed(transport.name, &bridge_addr_str, &message);
+                if let Err(e) = socks_conn.reject(0x01).await {
+                    tracing::debug!("Failed to refuse SOCKS5 connection: {:#}", e);
+                }
+                continue;
+            }
+        };
+        let socks_stream = match socks_conn.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::error!("Failed to accept SOCKS5 connection: {:#}", e);
+                continue;
+            }
+        };
 
-        let endpoint_clone = endpoint.clone();
+        let endpoints = endpoints.clone();
+        let pool = pool.clone();
+        let sessions = sessions.clone();
 
-        tokio::spawn(async move {
-            let quic_server_addr = match resolve_bridge_address(&quic_server_addr_str).await {
+        shutdown.spawn(async move {
+            let mut bridge_addr = match resolve_bridge_address(&bridge_addr_str).await {
                 Ok(addr) => addr,
                 Err(e) => {
-                    tracing::error!("Failed to resolve bridge address '{}': {}", quic_server_addr_str, e);
+                    tracing::error!("Failed to resolve bridge address '{}': {}", bridge_addr_str, e);
+                    report_connect_failed(transport.name, &bridge_addr_str, &format!("{:#}", e));
+                    return;
+                }
+            };
+            if let Some(port) = params.port {
+                bridge_addr.set_port(port);
+            }
+
+            let endpoint = match endpoints.for_addr(bridge_addr) {
+                Ok(endpoint) => endpoint.clone(),
+                Err(e) => {
+                    tracing::error!("Cannot reach bridge {}: {}", bridge_addr, e);
+                    report_connect_failed(transport.name, &bridge_addr.to_string(), &e.to_string());
                     return;
                 }
             };
 
             if let Err(e) = handle_socks_connection(
-                endpoint_clone,
+                &pool,
+                &sessions,
+                endpoint,
                 socks_stream,
-                quic_server_addr,
-                target_addr,
+                bridge_addr,
+                transport.name,
+                &params,
             ).await {
-                tracing::error!("Failed to handle SOCKS5 connection: {}", e);
+                tracing::error!("Failed to handle SOCKS5 connection: {:#}", e);
             }
         });
     }
 }
 
 async fn handle_socks_connection(
+    pool: &ConnectionPool,
+    sessions: &SessionCache,
     endpoint: Endpoint,
-    socks_stream: tokio::net::TcpStream,
-    quic_server_addr: std::net::SocketAddr,
-    _target_addr: std::net::SocketAddr,
+    mut socks_stream: tokio::net::TcpStream,
+    bridge_addr: SocketAddr,
+    transport: &str,
+    params: &BridgeParams,
 ) -> anyhow::Result<()> {
+    let (bridge, quic_send, quic_recv) = open_stream(
+        pool,
+        sessions,
+        &endpoint,
+        bridge_addr,
+        transport,
+        params,
+    ).await?;
+
+    let (quic_send, quic_recv) = if quic_recv.is_0rtt() {
+        send_early_data(&bridge, params, &mut socks_stream, quic_send, quic_recv).await?
+    } else {
+        (quic_send, quic_recv)
+    };
+
+    bridge_socks5_to_quic(socks_stream, quic_send, quic_recv).await
+}
+
+/// Opens a relay stream to the bridge on its pooled connection, dialing when
+/// there is none. A pooled connection that turns out to be dead is replaced once.
+async fn open_stream(
+    pool: &ConnectionPool,
+    sessions: &SessionCache,
+    endpoint: &Endpoint,
+    bridge_addr: SocketAddr,
+    transport: &str,
+    params: &BridgeParams,
+) -> anyhow::Result<(BridgeConnection, quinn::SendStream, quinn::RecvStream)> {
+    let connect = || connect_bridge(endpoint, sessions, bridge_addr, transport, params);
+
+    let bridge = pool.get_or_connect(bridge_addr, params, connect).await?;
+    match send_header(&bridge, params).await {
+        Ok((quic_send, quic_recv)) => return Ok((bridge, quic_send, quic_recv)),
+        Err(e) => {
+            tracing::debug!("Pooled connection to {} failed ({:#}), reconnecting", bridge_addr, e);
+            pool.evict(bridge_addr, params, &bridge.connection).await;
+        }
+    }
+
+    let bridge = pool.get_or_connect(bridge_addr, params, connect).await?;
+    let (quic_send, quic_recv) = send_header(&bridge, params).await?;
+    Ok((bridge, quic_send, quic_recv))
+}
+
+/// Opens a stream and writes its header. On a connection that is still
+/// resuming, the header leaves with the handshake as 0-RTT data, and whether
+/// it arrived is only known once [`send_early_data`] has waited for the bridge.
+async fn send_header(
+    bridge: &BridgeConnection,
+    params: &BridgeParams,
+) -> anyhow::Result<(quinn::SendStream, quinn::RecvStream)> {
     use anyhow::Context;
 
-    let connection = endpoint
-        .connect(quic_server_addr, "localhost")?
-        .await
-        .context("Failed to connect to QUIC server")?;
+    let mut header = StreamHeader::new(StreamKind::Relay);
+    header.token = params.auth.map(|auth| auth.token()).transpose()?;
 
-    let (quic_send, quic_recv) = connection
-        .open_bi()
+    let (mut quic_send, quic_recv) = bridge.connection.open_bi()
         .await
         .context("Failed to open bidirectional stream")?;
+    let written = header.write(&mut quic_send, params.padding).await;
 
-    bridge_socks5_to_quic(socks_stream, quic_send, quic_recv).await
+    if quic_recv.is_0rtt() {
+        // A write refused because the bridge already turned down 0-RTT is
+        // made up for by the resend in send_early_data.
+        if written.is_ok() {
+            tracing::debug!("Sent stream header as 0-RTT data");
+        }
+        return Ok((quic_send, quic_recv));
+    }
+
+    written.context("Failed to write stream header")?;
+
+    Ok((quic_send, quic_recv))
 }
 
-async fn bridge_socks5_to_quic(
-    mut socks_stream: tokio::net::TcpStream,
+/// Forwards what Tor writes, usually its TLS ClientHello, as 0-RTT data while
+/// the resumed handshake runs, keeping a copy. If the bridge rejects the early
+/// data, the header and the kept bytes are sent again on a fresh stream.
+async fn send_early_data(
+    bridge: &BridgeConnection,
+    params: &BridgeParams,
+    socks_stream: &mut tokio::net::TcpStream,
     mut quic_send: quinn::SendStream,
-    mut quic_recv: quinn::RecvStream,
-) -> anyhow::Result<()> {
+    quic_recv: quinn::RecvStream,
+) -> anyhow::Result<(quinn::SendStream, quinn::RecvStream)> {
     use anyhow::Context;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::AsyncReadExt;
 
-    tracing::info!("Starting bidirectional copy between SOCKS5 and QUIC");
+    let mut early_data = Vec::new();
+    let mut buf = vec![0u8; 4096];
 
-    let mut first_buf = vec![0u8; 1024];
+    let accepted = bridge.early_data_accepted();
+    tokio::pin!(accepted);
 
-    tokio::select! {
-        result = socks_stream.read(&mut first_buf) => {
-            match result {
-                Ok(0) => {
-                    tracing::warn!("SOCKS5 stream closed before sending data");
-                    return Ok(());
+    let accepted = loop {
+        tokio::select! {
+            accepted = &mut accepted => break accepted,
+            read = socks_stream.read(&mut buf), if early_data.len() < EARLY_DATA_LIMIT => {
+                let n = read.context("Failed to read from SOCKS5")?;
+                if n == 0 {
+                    break accepted.await;
                 }
-                Ok(n) => {
-                    tracing::info!("Read {} bytes from SOCKS5, writing to QUIC", n);
-                    quic_send.write_all(&first_buf[..n]).await
-                        .context("Failed to write first chunk to QUIC")?;
-                    quic_send.flush().await
-                        .context("Failed to flush QUIC stream")?;
-                    tracing::info!("Successfully wrote first chunk to QUIC");
-                }
-                Err(e) => {
-                    return Err(anyhow::Error::from(e).context("Failed to read from SOCKS5"));
+                early_data.extend_from_slice(&buf[..n]);
+                if quic_send.write_all(&buf[..n]).await.is_err() {
+                    break accepted.await;
                 }
             }
         }
-        _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {
-            tracing::warn!("Timeout waiting for first data from SOCKS5");
-            return Err(anyhow::anyhow!("Timeout waiting for SOCKS5 data"));
+    };
+
+    if accepted {
+        tracing::debug!("Bridge accepted {} bytes of 0-RTT data", early_data.len());
+        return Ok((quic_send, quic_recv));
+    }
+
+    tracing::debug!("Bridge rejected 0-RTT data, resending the stream header and {} bytes", early_data.len());
+
+    let (mut quic_send, quic_recv) = send_header(bridge, params).await?;
+    quic_send.write_all(&early_data).await
+        .context("Failed to resend 0-RTT data")?;
+
+    Ok((quic_send, quic_recv))
+}
+
+/// Dials a bridge, resuming with 0-RTT when an earlier connection left a
+/// session ticket. A resumed connection is returned before its handshake
+/// completes and reports to Tor once it does.
+async fn connect_bridge(
+    endpoint: &Endpoint,
+    sessions: &SessionCache,
+    bridge_addr: SocketAddr,
+    transport: &str,
+    params: &BridgeParams,
+) -> anyhow::Result<BridgeConnection> {
+    use anyhow::Context;
+
+    let address = bridge_addr.to_string();
+
+    let client_config = sessions.client_config(params)
+        .context("Failed to configure QUIC client")?;
+
+    let connecting = match endpoint.connect_with(client_config, bridge_addr, params.connect_name()) {
+        Ok(connecting) => connecting,
+        Err(e) => {
+            report_connect_failed(transport, &address, &e.to_string());
+            return Err(anyhow::Error::from(e).context("Failed to start QUIC connection"));
+        }
+    };
+
+    let connecting = match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            tracing::info!("Resuming session with bridge {} using 0-RTT", bridge_addr);
+
+            let bridge = BridgeConnection::resuming(connection, accepted);
+            let resumed = bridge.clone();
+            let transport = transport.to_string();
+            tokio::spawn(async move {
+                let accepted = resumed.early_data_accepted().await;
+                match resumed.connection.close_reason() {
+                    Some(e) if !accepted => {
+                        report_connect_failed(&transport, &address, &describe_connection_error(&e));
+                    }
+                    _ => {
+                        if !accepted {
+                            tracing::debug!("Bridge {} rejected 0-RTT data", address);
+                        }
+                        report_connected(&transport, &address);
+                    }
+                }
+            });
+
+            return Ok(bridge);
+        }
+        Err(connecting) => connecting,
+    };
+
+    let connection = match connecting.await {
+        Ok(connection) => {
+            report_connected(transport, &address);
+            connection
+        }
+        Err(e) => {
+            report_connect_failed(transport, &address, &describe_connection_error(&e));
+            return Err(anyhow::Error::from(e).context("Failed to connect to QUIC server"));
         }
+    };
+
+    tracing::info!("Connected to bridge {}", bridge_addr);
+
+    Ok(BridgeConnection::established(connection))
+}
+
+fn report_connected(transport: &str, address: &str) {
+    if let Err(e) = super::status(transport, &[("ADDRESS", address), ("CONNECT", "Success")]) {
+        tracing::warn!("Failed to send STATUS to Tor: {}", e);
     }
+}
 
-    let mut quic_stream = tokio::io::join(quic_recv, quic_send);
+/// Tells Tor about a failed bridge connection, both as `STATUS` for
+/// controllers and as `LOG` so the reason shows up in Tor's own log.
+fn report_connect_failed(transport: &str, address: &str, reason: &str) {
+    use super::{log, status};
 
-    let (to_quic, to_socks) = tokio::io::copy_bidirectional(
-        &mut socks_stream,
-        &mut quic_stream,
+    let result = status(
+        transport,
+        &[("ADDRESS", address), ("CONNECT", "Failed"), ("ERROR", reason)],
     )
-    .await
-    .context("Failed to copy bidirectional")?;
+    .and_then(|()| log(
+        LogSeverity::Warning,
+        &format!("{}: connection to bridge {} failed: {}", transport, address, reason),
+    ));
+    if let Err(e) = result {
+        tracing::warn!("Failed to send STATUS to Tor: {}", e);
+    }
+}
+
+/// Turns a QUIC handshake failure into a hint a bridge user can act on.
+fn describe_connection_error(error: &quinn::ConnectionError) -> String {
+    use quinn::ConnectionError;
+
+    match error {
+        ConnectionError::TimedOut => {
+            "bridge unreachable: no reply to the QUIC handshake (is UDP blocked?)".to_string()
+        }
+        ConnectionError::TransportError(e) if is_certificate_rejection(error) => {
+            format!("certificate mismatch, check cert= in the bridge line ({})", e.reason)
+        }
+        ConnectionError::ConnectionClosed(_) | ConnectionError::ApplicationClosed(_) => {
+            format!("bridge closed the connection during the handshake: {}", error)
+        }
+        _ => format!("QUIC handshake failed: {}", error),
+    }
+}
+
+/// Whether the handshake failed because we rejected the bridge's certificate,
+/// which with a pinned fingerprint means `cert=` does not match the bridge.
+pub(crate) fn is_certificate_rejection(error: &quinn::ConnectionError) -> bool {
+    use quinn::{ConnectionError, TransportErrorCode};
+    use rustls::AlertDescription;
+
+    match error {
+        ConnectionError::TransportError(e) => {
+            [AlertDescription::BadCertificate, AlertDescription::CertificateUnknown]
+                .into_iter()
+                .any(|alert| e.code == TransportErrorCode::crypto(alert.into()))
+        }
+        _ => false,
+    }
+}
+
+/// Relays between Tor's SOCKS connection and a stream whose header has been
+/// sent. Tor's bytes go out straight away; the bridge's only after it has
+/// accepted the stream.
+async fn bridge_socks5_to_quic(
+    mut socks_stream: tokio::net::TcpStream,
+    mut quic_send: quinn::SendStream,
+    mut quic_recv: quinn::RecvStream,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::io::AsyncWriteExt;
+
+    tracing::info!("Starting bidirectional copy between SOCKS5 and QUIC");
+
+    let (mut socks_read, mut socks_write) = socks_stream.split();
+
+    let to_quic = async {
+        let copied = tokio::io::copy(&mut socks_read, &mut quic_send).await?;
+        quic_send.finish()?;
+        anyhow::Ok(copied)
+    };
+    let to_socks = async {
+        match StreamStatus::read(&mut quic_recv).await? {
+            StreamStatus::Accepted => {}
+            status => anyhow::bail!("Bridge refused the stream: {}", status),
+        }
+        let copied = tokio::io::copy(&mut quic_recv, &mut socks_write).await?;
+        socks_write.shutdown().await?;
+        anyhow::Ok(copied)
+    };
+
+    let (to_quic, to_socks) = tokio::try_join!(to_quic, to_socks)
+        .context("Failed to copy bidirectional")?;
 
     tracing::debug!("Connection closed: {} bytes to QUIC, {} bytes to SOCKS5", to_quic, to_socks);
 
     Ok(())
 }
 
-async fn resolve_bridge_address(addr_str: &str) -> anyhow::Result<std::net::SocketAddr> {
+pub(crate) async fn resolve_bridge_address(addr_str: &str) -> anyhow::Result<std::net::SocketAddr> {
     use anyhow::Context;
 
     if let Ok(addr) = addr_str.parse::<std::net::SocketAddr>() {
@@ -0,0 +1,391 @@
+use super::replay::{BidiStream, ReplayConnection};
+use crate::shutdown::Shutdown;
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, HeaderMap, Method, Request, Response, StatusCode};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+type RequestStream = h3::server::RequestStream<BidiStream<Bytes>, Bytes>;
+
+/// Request bodies larger than this are refused rather than proxied.
+const MAX_REQUEST_BODY: usize = 1024 * 1024;
+
+/// Files are sent in chunks of this size.
+const FILE_CHUNK: usize = 64 * 1024;
+
+/// Headers that only make sense on one HTTP/1.1 hop and are not allowed in HTTP/3.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const NOT_FOUND_PAGE: &str = "<!DOCTYPE html>\n<html><head><title>404 Not Found</title></head>\
+<body><h1>Not Found</h1><p>The requested URL was not found on this server.</p></body></html>\n";
+
+/// The website shown over HTTP/3 to connections that do not authenticate as
+/// quictor clients, so that a prober finds an ordinary web server.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DecoySite {
+    /// Every request gets a 404.
+    #[default]
+    NotFound,
+    /// Static files, with `index.html` for directories. The path is canonical.
+    Directory(PathBuf),
+    /// Requests are forwarded over HTTP/1.1 to a local backend at `host:port`.
+    Proxy(http::uri::Authority),
+}
+
+impl FromStr for DecoySite {
+    type Err = anyhow::Error;
+
+    /// `none`, `http://host:port` for a backend, or a directory to serve.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        if s == "none" {
+            return Ok(DecoySite::NotFound);
+        }
+
+        if let Some(backend) = s.strip_prefix("http://") {
+            let authority = backend.strip_suffix('/').unwrap_or(backend);
+            let authority: http::uri::Authority = authority.parse()
+                .context(format!("Invalid decoy backend address: {}", s))?;
+            return Ok(DecoySite::Proxy(authority));
+        }
+
+        let path = std::fs::canonicalize(s)
+            .context(format!("Decoy site {} not found", s))?;
+        if !path.is_dir() {
+            anyhow::bail!("Decoy site {} is not a directory", path.display());
+        }
+
+        Ok(DecoySite::Directory(path))
+    }
+}
+
+impl fmt::Display for DecoySite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecoySite::NotFound => f.write_str("none"),
+            DecoySite::Directory(path) => write!(f, "{}", path.display()),
+            DecoySite::Proxy(authority) => write!(f, "http://{}", authority),
+        }
+    }
+}
+
+/// Answers HTTP/3 requests on `connection` from `site` until the client leaves.
+/// Requests are answered in tasks spawned through `shutdown`.
+pub async fn serve(connection: ReplayConnection, site: &DecoySite, shutdown: &Shutdown) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let mut h3_connection = h3::server::Connection::new(connection).await
+        .context("HTTP/3 handshake failed")?;
+
+    loop {
+        let (request, stream) = match h3_connection.accept().await {
+            Ok(Some(accepted)) => accepted,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::debug!("Decoy connection ended: {}", e);
+                break;
+            }
+        };
+
+        let site = site.clone();
+        shutdown.spawn(async move {
+            if let Err(e) = respond(request, stream, &site).await {
+                tracing::debug!("Failed to answer decoy request: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn respond(request: Request<()>, mut stream: RequestStream, site: &DecoySite) -> anyhow::Result<()> {
+    tracing::debug!("Decoy request: {} {}", request.method(), request.uri());
+
+    match site {
+        DecoySite::NotFound => send_not_found(&mut stream, request.method()).await,
+        DecoySite::Directory(root) => serve_file(request, &mut stream, root).await,
+        DecoySite::Proxy(backend) => {
+            if let Err(e) = proxy(request, &mut stream, backend).await {
+                tracing::warn!("Decoy backend {} failed: {:#}", backend, e);
+                // Only possible if no response has started yet; otherwise the
+                // client sees the stream end early, as with any broken backend.
+                let _ = send_status(&mut stream, StatusCode::BAD_GATEWAY).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn serve_file(request: Request<()>, stream: &mut RequestStream, root: &Path) -> anyhow::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let method = request.method();
+    if method != Method::GET && method != Method::HEAD {
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .header(header::CONTENT_LENGTH, 0)
+            .body(())?;
+        stream.send_response(response).await?;
+        return Ok(stream.finish().await?);
+    }
+
+    let Some(path) = resolve_file(root, request.uri().path()).await else {
+        return send_not_found(stream, method).await;
+    };
+
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return send_not_found(stream, method).await,
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return send_not_found(stream, method).await;
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type(&path))
+        .header(header::CONTENT_LENGTH, metadata.len())
+        .body(())?;
+    stream.send_response(response).await?;
+
+    if method == Method::GET {
+        loop {
+            let mut chunk = BytesMut::with_capacity(FILE_CHUNK);
+            if file.read_buf(&mut chunk).await? == 0 {
+                break;
+            }
+            stream.send_data(chunk.freeze()).await?;
+        }
+    }
+
+    Ok(stream.finish().await?)
+}
+
+/// The file a request path names under `root`, with symlinks resolved.
+/// Files that resolve to somewhere outside `root` are refused.
+async fn resolve_file(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut path = file_path(root, request_path)?;
+    if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+        path.push("index.html");
+    }
+
+    let path = tokio::fs::canonicalize(&path).await.ok()?;
+    path.starts_with(root).then_some(path)
+}
+
+/// Maps a request path onto a path under `root`, refusing anything that
+/// would step outside it.
+fn file_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+
+    let mut path = root.to_path_buf();
+    for component in Path::new(&decoded).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(path)
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    let decoded = String::from_utf8(decoded).ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+    Some(decoded)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn send_not_found(stream: &mut RequestStream, method: &Method) -> anyhow::Result<()> {
+    let response = Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CONTENT_LENGTH, NOT_FOUND_PAGE.len())
+        .body(())?;
+    stream.send_response(response).await?;
+
+    if method != Method::HEAD {
+        stream.send_data(Bytes::from_static(NOT_FOUND_PAGE.as_bytes())).await?;
+    }
+
+    Ok(stream.finish().await?)
+}
+
+async fn send_status(stream: &mut RequestStream, status: StatusCode) -> anyhow::Result<()> {
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_LENGTH, 0)
+        .body(())?;
+    stream.send_response(response).await?;
+
+    Ok(stream.finish().await?)
+}
+
+/// Forwards the request to `backend` over HTTP/1.1 and streams the answer back.
+async fn proxy(
+    request: Request<()>,
+    stream: &mut RequestStream,
+    backend: &http::uri::Authority,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use http_body_util::BodyExt;
+
+    let body = read_body(stream).await?;
+
+    let tcp = tokio::net::TcpStream::connect(backend.as_str()).await
+        .context("Failed to connect")?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(tcp))
+        .await
+        .context("HTTP/1.1 handshake failed")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("Decoy backend connection failed: {}", e);
+        }
+    });
+
+    let (parts, ()) = request.into_parts();
+    let host = parts.uri.authority()
+        .map(|authority| authority.to_string())
+        .or_else(|| parts.headers.get(header::HOST).and_then(|h| h.to_str().ok()).map(str::to_string))
+        .unwrap_or_else(|| backend.to_string());
+
+    let mut backend_request = Request::builder()
+        .method(parts.method)
+        .uri(parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+        .body(http_body_util::Full::new(body))?;
+    *backend_request.headers_mut() = without_hop_by_hop(parts.headers);
+    backend_request.headers_mut().insert(header::HOST, host.parse()?);
+
+    let response = sender.send_request(backend_request).await
+        .context("Request failed")?;
+
+    let (parts, mut body) = response.into_parts();
+    let mut head = Response::builder()
+        .status(parts.status)
+        .body(())?;
+    *head.headers_mut() = without_hop_by_hop(parts.headers);
+    stream.send_response(head).await?;
+
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.context("Failed to read response body")?.into_data() {
+            stream.send_data(data).await?;
+        }
+    }
+
+    Ok(stream.finish().await?)
+}
+
+async fn read_body(stream: &mut RequestStream) -> anyhow::Result<Bytes> {
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_REQUEST_BODY {
+            anyhow::bail!("Request body is larger than {} bytes", MAX_REQUEST_BODY);
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            let len = part.len();
+            body.extend_from_slice(part);
+            chunk.advance(len);
+        }
+    }
+
+    Ok(body.freeze())
+}
+
+fn without_hop_by_hop(mut headers: HeaderMap) -> HeaderMap {
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_out_of_the_root() {
+        let dir = std::env::temp_dir().join(format!("quictor-decoy-test-{}", std::process::id()));
+        let root = dir.join("www");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/index.html"), "inside").unwrap();
+        std::fs::write(dir.join("secret.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("leak.txt")).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+        std::os::unix::fs::symlink("sub/index.html", root.join("alias.html")).unwrap();
+
+        let DecoySite::Directory(root) = root.to_str().unwrap().parse().unwrap() else {
+            panic!("not a directory site");
+        };
+        let index = root.join("sub/index.html");
+        let resolved = (
+            resolve_file(&root, "/sub/").await,
+            resolve_file(&root, "/alias.html").await,
+            resolve_file(&root, "/leak.txt").await,
+            resolve_file(&root, "/up/secret.txt").await,
+            resolve_file(&root, "/%2e%2e/secret.txt").await,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(resolved.0.as_ref(), Some(&index));
+        assert_eq!(resolved.1.as_ref(), Some(&index));
+        assert_eq!(resolved.2, None);
+        assert_eq!(resolved.3, None);
+        assert_eq!(resolved.4, None);
+    }
+}
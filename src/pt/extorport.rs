@@ -0,0 +1,149 @@
+use super::env::OutboundBind;
+use aws_lc_rs::hmac;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const COOKIE_HEADER: &[u8; 32] = b"! Extended ORPort Auth Cookie !\x0a";
+const AUTH_TYPE_SAFE_COOKIE: u8 = 0x01;
+
+const SERVER_HASH_CONTEXT: &[u8] = b"ExtORPort authentication server-to-client hash";
+const CLIENT_HASH_CONTEXT: &[u8] = b"ExtORPort authentication client-to-server hash";
+
+const CMD_DONE: u16 = 0x0000;
+const CMD_USERADDR: u16 = 0x0001;
+const CMD_TRANSPORT: u16 = 0x0002;
+const REPLY_OKAY: u16 = 0x1000;
+
+/// Tor's Extended ORPort, which accepts the client address and transport name
+/// ahead of the relayed traffic so Tor can keep per-country and per-transport
+/// statistics.
+#[derive(Clone)]
+pub struct ExtOrPort {
+    addr: SocketAddr,
+    cookie: [u8; 32],
+    outbound_bind: OutboundBind,
+}
+
+// Keeps the cookie out of logs.
+impl fmt::Debug for ExtOrPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtOrPort").field("addr", &self.addr).finish_non_exhaustive()
+    }
+}
+
+impl ExtOrPort {
+    /// Reads the authentication cookie from `TOR_PT_AUTH_COOKIE_FILE`.
+    pub fn new(addr: SocketAddr, cookie_file: &Path, outbound_bind: OutboundBind) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let contents = std::fs::read(cookie_file)
+            .context(format!("Failed to read auth cookie file {}", cookie_file.display()))?;
+
+        if contents.len() != 64 || !contents.starts_with(COOKIE_HEADER) {
+            anyhow::bail!("Malformed auth cookie file {}", cookie_file.display());
+        }
+
+        let mut cookie = [0u8; 32];
+        cookie.copy_from_slice(&contents[32..]);
+
+        Ok(ExtOrPort { addr, cookie, outbound_bind })
+    }
+
+    /// Connects, authenticates and announces `client_addr` and `transport`,
+    /// returning a stream ready to relay the client's traffic.
+    pub async fn connect(&self, client_addr: SocketAddr, transport: &str) -> anyhow::Result<TcpStream> {
+        use anyhow::Context;
+
+        let mut stream = self.outbound_bind.connect_tcp(self.addr)
+            .await
+            .context("Failed to connect to Extended ORPort")?;
+
+        self.authenticate(&mut stream).await
+            .context("Extended ORPort authentication failed")?;
+
+        send_command(&mut stream, CMD_USERADDR, client_addr.to_string().as_bytes()).await?;
+        send_command(&mut stream, CMD_TRANSPORT, transport.as_bytes()).await?;
+        send_command(&mut stream, CMD_DONE, &[]).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await
+            .context("Failed to read Extended ORPort reply")?;
+        let command = u16::from_be_bytes([reply[0], reply[1]]);
+        let body_len = u16::from_be_bytes([reply[2], reply[3]]) as usize;
+        let mut body = vec![0u8; body_len];
+        stream.read_exact(&mut body).await
+            .context("Failed to read Extended ORPort reply body")?;
+
+        if command != REPLY_OKAY {
+            anyhow::bail!("Extended ORPort denied the connection (reply {:#06x})", command);
+        }
+
+        Ok(stream)
+    }
+
+    /// Runs the SAFE_COOKIE handshake from the Extended ORPort specification.
+    async fn authenticate(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
+        use anyhow::{Context, bail};
+
+        let mut auth_types = Vec::new();
+        loop {
+            let auth_type = stream.read_u8().await
+                .context("Failed to read auth types")?;
+            if auth_type == 0 {
+                break;
+            }
+            auth_types.push(auth_type);
+        }
+        if !auth_types.contains(&AUTH_TYPE_SAFE_COOKIE) {
+            bail!("Server does not offer SAFE_COOKIE authentication");
+        }
+        stream.write_u8(AUTH_TYPE_SAFE_COOKIE).await?;
+
+        let mut client_nonce = [0u8; 32];
+        aws_lc_rs::rand::fill(&mut client_nonce)
+            .map_err(|_| anyhow::anyhow!("Failed to generate client nonce"))?;
+        stream.write_all(&client_nonce).await?;
+
+        let mut server_hash = [0u8; 32];
+        let mut server_nonce = [0u8; 32];
+        stream.read_exact(&mut server_hash).await
+            .context("Failed to read server hash")?;
+        stream.read_exact(&mut server_nonce).await
+            .context("Failed to read server nonce")?;
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.cookie);
+        let nonces = [client_nonce, server_nonce].concat();
+
+        hmac::verify(&key, &[SERVER_HASH_CONTEXT, &nonces].concat(), &server_hash)
+            .map_err(|_| anyhow::anyhow!("Server hash does not match the auth cookie"))?;
+
+        let client_hash = hmac::sign(&key, &[CLIENT_HASH_CONTEXT, &nonces].concat());
+        stream.write_all(client_hash.as_ref()).await?;
+
+        let status = stream.read_u8().await
+            .context("Failed to read authentication status")?;
+        if status != 1 {
+            bail!("Server rejected our client hash");
+        }
+
+        Ok(())
+    }
+}
+
+async fn send_command(stream: &mut TcpStream, command: u16, body: &[u8]) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let body_len = u16::try_from(body.len())
+        .context("Extended ORPort command body too long")?;
+
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.extend_from_slice(&command.to_be_bytes());
+    message.extend_from_slice(&body_len.to_be_bytes());
+    message.extend_from_slice(body);
+
+    stream.write_all(&message).await
+        .context("Failed to write Extended ORPort command")
+}
@@ -0,0 +1,290 @@
+use crate::config::PaddingProfile;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version of the stream setup exchange sent by this build.
+///
+/// As the first byte of a stream it reads as HTTP/3 frame type 0x02, the
+/// HTTP/2 PRIORITY frame that HTTP/3 forbids (RFC 9114, section 7.2.8). No
+/// real HTTP/3 request starts with it, so one is told apart from a header
+/// after a single byte and goes to the decoy site without delay.
+pub const STREAM_PROTOCOL_VERSION: u8 = 2;
+
+/// What a client wants a stream used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// Relay the stream to the bridge's Tor ORPort.
+    Relay,
+}
+
+impl StreamKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            StreamKind::Relay => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(StreamKind::Relay),
+            _ => None,
+        }
+    }
+}
+
+/// The first thing a client writes on every bidirectional stream:
+///
+/// ```text
+/// u8   version
+/// u8   kind
+/// u8   target length, then the target as UTF-8
+/// u8   token length, then the token
+/// u16  padding length, then that many padding bytes
+/// ```
+///
+/// A header whose version or kind the server does not know is still read in
+/// full where possible, so it can answer with a [`StreamStatus`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub version: u8,
+    /// `None` when the kind byte is not one this build knows.
+    pub kind: Option<StreamKind>,
+    /// Where the stream should go, for kinds that need one.
+    pub target: Option<String>,
+    /// Proof that the client may use the bridge, for bridges that ask for one.
+    pub token: Option<Vec<u8>>,
+}
+
+impl StreamHeader {
+    pub fn new(kind: StreamKind) -> Self {
+        StreamHeader {
+            version: STREAM_PROTOCOL_VERSION,
+            kind: Some(kind),
+            target: None,
+            token: None,
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W, padding: PaddingProfile) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        let kind = self.kind.context("Cannot send a stream header without a kind")?;
+        let target = self.target.as_deref().unwrap_or_default().as_bytes();
+        let token = self.token.as_deref().unwrap_or_default();
+
+        let mut buf = vec![self.version, kind.to_byte()];
+        put_short(&mut buf, target).context("Stream target is too long")?;
+        put_short(&mut buf, token).context("Stream token is too long")?;
+        put_padding(&mut buf, padding);
+
+        writer.write_all(&buf).await?;
+
+        Ok(())
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let version = reader.read_u8().await
+            .context("Stream ended before its header")?;
+        if version != STREAM_PROTOCOL_VERSION {
+            return Ok(StreamHeader {
+                version,
+                kind: None,
+                target: None,
+                token: None,
+            });
+        }
+
+        let kind = StreamKind::from_byte(reader.read_u8().await?);
+        let target = read_short(reader).await?;
+        let token = read_short(reader).await?;
+        skip_padding(reader).await?;
+
+        let target = match target {
+            Some(target) => Some(String::from_utf8(target).context("Stream target is not UTF-8")?),
+            None => None,
+        };
+
+        Ok(StreamHeader {
+            version,
+            kind,
+            target,
+            token,
+        })
+    }
+}
+
+/// The server's answer to a [`StreamHeader`], sent before any relayed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStatus {
+    Accepted,
+    UnsupportedVersion,
+    UnsupportedKind,
+    Unauthorized,
+    /// The server could not reach where the stream was meant to go.
+    Unavailable,
+}
+
+impl StreamStatus {
+    fn to_byte(self) -> u8 {
+        match self {
+            StreamStatus::Accepted => 0,
+            StreamStatus::UnsupportedVersion => 1,
+            StreamStatus::UnsupportedKind => 2,
+            StreamStatus::Unauthorized => 3,
+            StreamStatus::Unavailable => 4,
+        }
+    }
+
+    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(StreamStatus::Accepted),
+            1 => Ok(StreamStatus::UnsupportedVersion),
+            2 => Ok(StreamStatus::UnsupportedKind),
+            3 => Ok(StreamStatus::Unauthorized),
+            4 => Ok(StreamStatus::Unavailable),
+            _ => anyhow::bail!("Unknown stream status {}", byte),
+        }
+    }
+
+    /// Sends the reply: version, status and `u16`-prefixed padding.
+    pub async fn write<W: AsyncWrite + Unpin>(self, writer: &mut W, padding: PaddingProfile) -> anyhow::Result<()> {
+        let mut buf = vec![STREAM_PROTOCOL_VERSION, self.to_byte()];
+        put_padding(&mut buf, padding);
+
+        writer.write_all(&buf).await?;
+
+        Ok(())
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let version = reader.read_u8().await
+            .context("Stream ended before the bridge answered")?;
+        let status = StreamStatus::from_byte(reader.read_u8().await?)?;
+        skip_padding(reader).await?;
+
+        if version != STREAM_PROTOCOL_VERSION && status == StreamStatus::Accepted {
+            anyhow::bail!("Bridge answered with stream protocol version {}", version);
+        }
+
+        Ok(status)
+    }
+}
+
+impl fmt::Display for StreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            StreamStatus::Accepted => "accepted",
+            StreamStatus::UnsupportedVersion => "unsupported stream protocol version",
+            StreamStatus::UnsupportedKind => "unsupported stream kind",
+            StreamStatus::Unauthorized => "not authorized",
+            StreamStatus::Unavailable => "destination unavailable",
+        };
+        f.write_str(message)
+    }
+}
+
+fn put_short(buf: &mut Vec<u8>, field: &[u8]) -> anyhow::Result<()> {
+    buf.push(u8::try_from(field.len())?);
+    buf.extend_from_slice(field);
+    Ok(())
+}
+
+fn put_padding(buf: &mut Vec<u8>, padding: PaddingProfile) {
+    let len = padding.padding_len().min(usize::from(u16::MAX));
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.resize(buf.len() + len, 0);
+}
+
+async fn read_short<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let len = reader.read_u8().await?;
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let mut field = vec![0u8; usize::from(len)];
+    reader.read_exact(&mut field).await?;
+
+    Ok(Some(field))
+}
+
+async fn skip_padding<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<()> {
+    let len = reader.read_u16().await?;
+    let mut padding = vec![0u8; usize::from(len)];
+    reader.read_exact(&mut padding).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_headers() {
+        let profiles = [PaddingProfile::None, PaddingProfile::Light, PaddingProfile::Heavy];
+        for padding in profiles {
+            for token in [None, Some(vec![0xab; 48])] {
+                let header = StreamHeader {
+                    target: Some("127.0.0.1:9001".to_string()),
+                    token,
+                    ..StreamHeader::new(StreamKind::Relay)
+                };
+                let (mut client, mut server) = tokio::io::duplex(4096);
+
+                header.write(&mut client, padding).await.unwrap();
+                client.write_all(b"data").await.unwrap();
+
+                assert_eq!(StreamHeader::read(&mut server).await.unwrap(), header);
+                let mut data = [0u8; 4];
+                server.read_exact(&mut data).await.unwrap();
+                assert_eq!(&data, b"data", "padding {:?} not fully skipped", padding);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_statuses() {
+        let (mut server, mut client) = tokio::io::duplex(4096);
+
+        StreamStatus::Unauthorized.write(&mut server, PaddingProfile::Heavy).await.unwrap();
+        StreamStatus::Accepted.write(&mut server, PaddingProfile::None).await.unwrap();
+
+        assert_eq!(StreamStatus::read(&mut client).await.unwrap(), StreamStatus::Unauthorized);
+        assert_eq!(StreamStatus::read(&mut client).await.unwrap(), StreamStatus::Accepted);
+    }
+
+    #[tokio::test]
+    async fn stops_at_an_http3_frame_type() {
+        // The start of an HTTP/3 request: a HEADERS frame (type 0x01).
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(&[0x01, 0x1d, 0x00, 0x00]).await.unwrap();
+
+        let header = StreamHeader::read(&mut server).await.unwrap();
+        assert_ne!(header.version, STREAM_PROTOCOL_VERSION);
+        assert_eq!(header.kind, None);
+
+        // Nothing past the first byte was consumed, and the client's writer
+        // still being open shows read did not wait for more.
+        let mut rest = [0u8; 3];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest, [0x1d, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_headers() {
+        let header = StreamHeader {
+            token: Some(vec![1; 32]),
+            ..StreamHeader::new(StreamKind::Relay)
+        };
+        let mut buf = Vec::new();
+        header.write(&mut buf, PaddingProfile::None).await.unwrap();
+
+        for len in 0..buf.len() {
+            assert!(StreamHeader::read(&mut &buf[..len]).await.is_err(), "read {} bytes", len);
+        }
+        assert_eq!(StreamHeader::read(&mut &buf[..]).await.unwrap(), header);
+    }
+}
@@ -1,6 +1,18 @@
+pub mod args;
+pub mod bridge;
+pub mod decoy;
 pub mod env;
+pub mod pool;
+pub mod replay;
+pub mod extorport;
+pub mod header;
 pub mod client;
 pub mod server;
+pub mod session;
+pub mod transport;
+
+use args::PtArgs;
+use std::net::SocketAddr;
 
 pub const PT_VERSION: &str = "1";
 
@@ -21,7 +33,33 @@ pub fn detect_mode() -> anyhow::Result<PtMode> {
         return Ok(PtMode::Server);
     }
 
-    bail!("Neither TOR_PT_CLIENT_TRANSPORTS nor TOR_PT_SERVER_TRANSPORTS is set")
+    let message = "Neither TOR_PT_CLIENT_TRANSPORTS nor TOR_PT_SERVER_TRANSPORTS is set";
+    env_error(message)?;
+    bail!(message)
+}
+
+/// Checks that Tor speaks a PT protocol version we support and announces it.
+///
+/// Writes `VERSION 1` on success, or `ENV-ERROR`/`VERSION-ERROR no-version`
+/// before returning an error.
+pub fn negotiate_version() -> anyhow::Result<()> {
+    use anyhow::bail;
+
+    let versions = match std::env::var("TOR_PT_MANAGED_TRANSPORT_VER") {
+        Ok(versions) => versions,
+        Err(_) => {
+            let message = "TOR_PT_MANAGED_TRANSPORT_VER not set";
+            env_error(message)?;
+            bail!(message);
+        }
+    };
+
+    if !versions.split(',').any(|v| v.trim() == PT_VERSION) {
+        write_pt_message("VERSION-ERROR no-version")?;
+        bail!("No supported PT version in TOR_PT_MANAGED_TRANSPORT_VER: {}", versions);
+    }
+
+    write_pt_message(&format!("VERSION {}", PT_VERSION))
 }
 
 pub fn write_pt_message(message: &str) -> anyhow::Result<()> {
@@ -32,3 +70,126 @@ pub fn write_pt_message(message: &str) -> anyhow::Result<()> {
     
     Ok(())
 }
+
+/// Reports a missing or malformed `TOR_PT_*` variable.
+pub fn env_error(message: &str) -> anyhow::Result<()> {
+    write_pt_message(&format!("ENV-ERROR {}", single_line(message)))
+}
+
+/// Confirms that the `TOR_PT_PROXY` upstream proxy is in use.
+pub fn proxy_done() -> anyhow::Result<()> {
+    write_pt_message("PROXY DONE")
+}
+
+/// Reports that the `TOR_PT_PROXY` upstream proxy cannot be used.
+pub fn proxy_error(message: &str) -> anyhow::Result<()> {
+    write_pt_message(&format!("PROXY-ERROR {}", single_line(message)))
+}
+
+/// Announces a client transport's SOCKS listener.
+pub fn cmethod(transport: &str, socks_addr: SocketAddr) -> anyhow::Result<()> {
+    write_pt_message(&format!("CMETHOD {} socks5 {}", transport, socks_addr))
+}
+
+/// Reports a client transport that could not be started.
+pub fn cmethod_error(transport: &str, message: &str) -> anyhow::Result<()> {
+    write_pt_message(&format!("CMETHOD-ERROR {} {}", transport, single_line(message)))
+}
+
+pub fn cmethods_done() -> anyhow::Result<()> {
+    write_pt_message("CMETHODS DONE")
+}
+
+/// Announces a server transport's bind address and, if any, its ARGS.
+pub fn smethod(transport: &str, bind_addr: SocketAddr, args: &PtArgs) -> anyhow::Result<()> {
+    if args.is_empty() {
+        write_pt_message(&format!("SMETHOD {} {}", transport, bind_addr))
+    } else {
+        write_pt_message(&format!(
+            "SMETHOD {} {} ARGS:{}",
+            transport,
+            bind_addr,
+            args.to_smethod_args()
+        ))
+    }
+}
+
+/// Reports a server transport that could not be started.
+pub fn smethod_error(transport: &str, message: &str) -> anyhow::Result<()> {
+    write_pt_message(&format!("SMETHOD-ERROR {} {}", transport, single_line(message)))
+}
+
+pub fn smethods_done() -> anyhow::Result<()> {
+    write_pt_message("SMETHODS DONE")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSeverity {
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+impl LogSeverity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogSeverity::Error => "error",
+            LogSeverity::Warning => "warning",
+            LogSeverity::Notice => "notice",
+            LogSeverity::Info => "info",
+            LogSeverity::Debug => "debug",
+        }
+    }
+}
+
+/// Sends a message to Tor's log.
+pub fn log(severity: LogSeverity, message: &str) -> anyhow::Result<()> {
+    write_pt_message(&format!(
+        "LOG SEVERITY={} MESSAGE={}",
+        severity.as_str(),
+        quote_value(message)
+    ))
+}
+
+/// Reports transport status to Tor as `STATUS TRANSPORT=<transport> K=V ...`.
+pub fn status(transport: &str, fields: &[(&str, &str)]) -> anyhow::Result<()> {
+    let mut message = format!("STATUS TRANSPORT={}", transport);
+    for (key, value) in fields {
+        message.push_str(&format!(" {}={}", key, quote_value(value)));
+    }
+    write_pt_message(&message)
+}
+
+/// Encodes a `LOG`/`STATUS` value, quoting it as a C string when it isn't a
+/// bare word, the way Tor's key-value line parser expects.
+fn quote_value(value: &str) -> String {
+    let bare = !value.is_empty()
+        && value.bytes().all(|b| b.is_ascii_graphic() && !matches!(b, b'"' | b'\\' | b'='));
+    if bare {
+        return value.to_string();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for b in value.bytes() {
+        match b {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            b' ' => quoted.push(' '),
+            b if b.is_ascii_graphic() => quoted.push(b as char),
+            b => quoted.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// PT messages are line-based, so embedded newlines would start a bogus message.
+fn single_line(message: &str) -> String {
+    message.replace(['\r', '\n'], " ")
+}
@@ -0,0 +1,141 @@
+use super::bridge::BridgeParams;
+use quinn::Connection;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+type Slot = Arc<tokio::sync::Mutex<Option<BridgeConnection>>>;
+type Key = (SocketAddr, BridgeParams);
+
+/// A connection to a bridge, which may still be resuming a session with 0-RTT.
+#[derive(Debug, Clone)]
+pub struct BridgeConnection {
+    pub connection: Connection,
+    /// `None` until the handshake completes, then whether 0-RTT data was kept.
+    early_data: watch::Receiver<Option<bool>>,
+}
+
+impl BridgeConnection {
+    /// A connection whose handshake has completed.
+    pub fn established(connection: Connection) -> Self {
+        let (_, early_data) = watch::channel(Some(true));
+        BridgeConnection { connection, early_data }
+    }
+
+    /// A connection sending 0-RTT data until `accepted` resolves.
+    pub fn resuming(connection: Connection, accepted: quinn::ZeroRttAccepted) -> Self {
+        let (sender, early_data) = watch::channel(None);
+        tokio::spawn(async move {
+            let _ = sender.send(Some(accepted.await));
+        });
+        BridgeConnection { connection, early_data }
+    }
+
+    /// Waits for the handshake, then tells whether the bridge accepted what
+    /// was sent as 0-RTT data. Streams opened before a rejection are void.
+    pub async fn early_data_accepted(&self) -> bool {
+        if let Some(accepted) = *self.early_data.borrow() {
+            return accepted;
+        }
+        let mut early_data = self.early_data.clone();
+        let accepted = match early_data.wait_for(Option::is_some).await {
+            Ok(accepted) => accepted.unwrap_or(false),
+            Err(_) => false,
+        };
+        accepted
+    }
+}
+
+/// Keeps one live QUIC connection per bridge so that SOCKS sessions become
+/// streams on it instead of each paying for a handshake.
+///
+/// Bridges are keyed by resolved address and bridge parameters, so two bridge
+/// lines for the same address with different `cert=` never share a connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionPool {
+    slots: Arc<Mutex<HashMap<Key, Slot>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the bridge's live connection, or the one `connect` makes if
+    /// there is none. Concurrent callers for one bridge share a single handshake.
+    pub async fn get_or_connect<F, Fut>(
+        &self,
+        addr: SocketAddr,
+        params: &BridgeParams,
+        connect: F,
+    ) -> anyhow::Result<BridgeConnection>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<BridgeConnection>>,
+    {
+        let slot = self.slot(addr, params);
+        let mut current = slot.lock().await;
+
+        if let Some(bridge) = current.as_ref() {
+            if bridge.connection.close_reason().is_none() {
+                return Ok(bridge.clone());
+            }
+            tracing::debug!("Connection to bridge {} has closed, reconnecting", addr);
+        }
+
+        let bridge = match connect().await {
+            Ok(bridge) => bridge,
+            Err(e) => {
+                *current = None;
+                drop(current);
+                self.prune(&(addr, params.clone()));
+                return Err(e);
+            }
+        };
+        *current = Some(bridge.clone());
+
+        // Drop the slot once the connection closes, so that bridges no longer
+        // in use do not stay in the map for good.
+        let pool = self.clone();
+        let key = (addr, params.clone());
+        let connection = bridge.connection.clone();
+        tokio::spawn(async move {
+            connection.closed().await;
+            pool.prune(&key);
+        });
+
+        Ok(bridge)
+    }
+
+    /// Forgets `connection` so that the next caller dials a fresh one.
+    pub async fn evict(&self, addr: SocketAddr, params: &BridgeParams, connection: &Connection) {
+        let slot = self.slot(addr, params);
+        let mut current = slot.lock().await;
+
+        if current.as_ref().is_some_and(|c| c.connection.stable_id() == connection.stable_id()) {
+            *current = None;
+        }
+        drop(current);
+        self.prune(&(addr, params.clone()));
+    }
+
+    /// Removes the slot for `key` if it holds no live connection. A slot that
+    /// someone is dialing on is left alone; they will fill it.
+    fn prune(&self, key: &Key) {
+        let mut slots = self.slots.lock().unwrap();
+        let dead = slots.get(key).is_some_and(|slot| match slot.try_lock() {
+            Ok(current) => current.as_ref().is_none_or(|c| c.connection.close_reason().is_some()),
+            Err(_) => false,
+        });
+        if dead {
+            slots.remove(key);
+        }
+    }
+
+    fn slot(&self, addr: SocketAddr, params: &BridgeParams) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        slots.entry((addr, params.clone())).or_default().clone()
+    }
+}
@@ -0,0 +1,412 @@
+use bytes::{Buf, Bytes};
+use h3::quic::{self, StreamId, WriteBuf};
+use quinn::VarInt;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Largest chunk handed to h3 per read.
+const READ_CHUNK: usize = 16 * 1024;
+
+type Pending<T> = Pin<Box<dyn Future<Output = Result<T, quinn::ConnectionError>> + Send>>;
+
+/// Reads a stream while keeping a copy of every byte read, so that the
+/// stream can be handed on as if it had not been touched.
+pub struct RecordingReader {
+    recv: quinn::RecvStream,
+    read: Vec<u8>,
+}
+
+impl RecordingReader {
+    pub fn new(recv: quinn::RecvStream) -> Self {
+        RecordingReader { recv, read: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> quinn::RecvStream {
+        self.recv
+    }
+
+    /// The stream, with what was read so far queued to be read again.
+    pub fn into_partly_read(self, send: quinn::SendStream) -> PartlyRead {
+        PartlyRead {
+            send,
+            recv: self.recv,
+            read: Bytes::from(self.read),
+        }
+    }
+}
+
+impl AsyncRead for RecordingReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.recv).poll_read(cx, buf))?;
+        this.read.extend_from_slice(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A bidirectional stream of which the server has already read `read`.
+pub struct PartlyRead {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    read: Bytes,
+}
+
+/// A QUIC connection for the h3 server whose first bidirectional stream is
+/// one the server already started reading, for instance to look for a
+/// stream header. h3 sees that stream from its first byte.
+pub struct ReplayConnection {
+    connection: quinn::Connection,
+    first: Option<PartlyRead>,
+    accepting_bi: Option<Pending<(quinn::SendStream, quinn::RecvStream)>>,
+    accepting_uni: Option<Pending<quinn::RecvStream>>,
+    opener: Opener,
+}
+
+impl ReplayConnection {
+    pub fn new(connection: quinn::Connection, first: Option<PartlyRead>) -> Self {
+        ReplayConnection {
+            opener: Opener::new(connection.clone()),
+            connection,
+            first,
+            accepting_bi: None,
+            accepting_uni: None,
+        }
+    }
+}
+
+impl<B: Buf> quic::Connection<B> for ReplayConnection {
+    type RecvStream = RecvStream;
+    type OpenStreams = Opener;
+    type AcceptError = QuicError;
+
+    fn poll_accept_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<RecvStream>, QuicError>> {
+        let connection = self.connection.clone();
+        let accepting = self.accepting_uni
+            .get_or_insert_with(|| Box::pin(async move { connection.accept_uni().await }));
+        let result = ready!(accepting.as_mut().poll(cx));
+        self.accepting_uni = None;
+
+        Poll::Ready(Ok(Some(RecvStream::new(result?, Bytes::new()))))
+    }
+
+    fn poll_accept_bidi(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<BidiStream<B>>, QuicError>> {
+        if let Some(first) = self.first.take() {
+            return Poll::Ready(Ok(Some(BidiStream::new(first.send, first.recv, first.read))));
+        }
+
+        let connection = self.connection.clone();
+        let accepting = self.accepting_bi
+            .get_or_insert_with(|| Box::pin(async move { connection.accept_bi().await }));
+        let result = ready!(accepting.as_mut().poll(cx));
+        self.accepting_bi = None;
+
+        let (send, recv) = result?;
+        Poll::Ready(Ok(Some(BidiStream::new(send, recv, Bytes::new()))))
+    }
+
+    fn opener(&self) -> Opener {
+        Opener::new(self.connection.clone())
+    }
+}
+
+impl<B: Buf> quic::OpenStreams<B> for ReplayConnection {
+    type BidiStream = BidiStream<B>;
+    type SendStream = SendStream<B>;
+    type OpenError = QuicError;
+
+    fn poll_open_bidi(&mut self, cx: &mut Context<'_>) -> Poll<Result<BidiStream<B>, QuicError>> {
+        self.opener.poll_open_bidi(cx)
+    }
+
+    fn poll_open_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<SendStream<B>, QuicError>> {
+        self.opener.poll_open_send(cx)
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        <Opener as quic::OpenStreams<B>>::close(&mut self.opener, code, reason)
+    }
+}
+
+/// Opens the streams the h3 server sends its own control data on.
+pub struct Opener {
+    connection: quinn::Connection,
+    opening_bi: Option<Pending<(quinn::SendStream, quinn::RecvStream)>>,
+    opening_uni: Option<Pending<quinn::SendStream>>,
+}
+
+impl Opener {
+    fn new(connection: quinn::Connection) -> Self {
+        Opener {
+            connection,
+            opening_bi: None,
+            opening_uni: None,
+        }
+    }
+}
+
+impl<B: Buf> quic::OpenStreams<B> for Opener {
+    type BidiStream = BidiStream<B>;
+    type SendStream = SendStream<B>;
+    type OpenError = QuicError;
+
+    fn poll_open_bidi(&mut self, cx: &mut Context<'_>) -> Poll<Result<BidiStream<B>, QuicError>> {
+        let connection = self.connection.clone();
+        let opening = self.opening_bi
+            .get_or_insert_with(|| Box::pin(async move { connection.open_bi().await }));
+        let result = ready!(opening.as_mut().poll(cx));
+        self.opening_bi = None;
+
+        let (send, recv) = result?;
+        Poll::Ready(Ok(BidiStream::new(send, recv, Bytes::new())))
+    }
+
+    fn poll_open_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<SendStream<B>, QuicError>> {
+        let connection = self.connection.clone();
+        let opening = self.opening_uni
+            .get_or_insert_with(|| Box::pin(async move { connection.open_uni().await }));
+        let result = ready!(opening.as_mut().poll(cx));
+        self.opening_uni = None;
+
+        Poll::Ready(Ok(SendStream::new(result?)))
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        let code = VarInt::from_u64(code.value()).unwrap_or(VarInt::MAX);
+        self.connection.close(code, reason);
+    }
+}
+
+pub struct BidiStream<B: Buf> {
+    send: SendStream<B>,
+    recv: RecvStream,
+}
+
+impl<B: Buf> BidiStream<B> {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream, read: Bytes) -> Self {
+        BidiStream {
+            send: SendStream::new(send),
+            recv: RecvStream::new(recv, read),
+        }
+    }
+}
+
+impl<B: Buf> quic::BidiStream<B> for BidiStream<B> {
+    type SendStream = SendStream<B>;
+    type RecvStream = RecvStream;
+
+    fn split(self) -> (SendStream<B>, RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for BidiStream<B> {
+    type Error = QuicError;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), QuicError>> {
+        self.send.poll_ready(cx)
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), QuicError> {
+        self.send.send_data(data)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), QuicError>> {
+        self.send.poll_finish(cx)
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        self.send.reset(reset_code)
+    }
+
+    fn send_id(&self) -> StreamId {
+        self.send.send_id()
+    }
+}
+
+impl<B: Buf> quic::RecvStream for BidiStream<B> {
+    type Buf = Bytes;
+    type Error = QuicError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, QuicError>> {
+        self.recv.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.recv.stop_sending(error_code)
+    }
+
+    fn recv_id(&self) -> StreamId {
+        self.recv.recv_id()
+    }
+}
+
+pub struct SendStream<B: Buf> {
+    stream: quinn::SendStream,
+    writing: Option<WriteBuf<B>>,
+}
+
+impl<B: Buf> SendStream<B> {
+    fn new(stream: quinn::SendStream) -> Self {
+        SendStream { stream, writing: None }
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for SendStream<B> {
+    type Error = QuicError;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), QuicError>> {
+        if let Some(data) = self.writing.as_mut() {
+            while data.has_remaining() {
+                let written = ready!(Pin::new(&mut self.stream).poll_write(cx, data.chunk()))?;
+                data.advance(written);
+            }
+        }
+        self.writing = None;
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), QuicError> {
+        if self.writing.is_some() {
+            return Err(QuicError::NotReady);
+        }
+        self.writing = Some(data.into());
+        Ok(())
+    }
+
+    fn poll_finish(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), QuicError>> {
+        Poll::Ready(self.stream.finish().map_err(QuicError::from))
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        let _ = self.stream.reset(VarInt::from_u64(reset_code).unwrap_or(VarInt::MAX));
+    }
+
+    fn send_id(&self) -> StreamId {
+        stream_id(self.stream.id())
+    }
+}
+
+pub struct RecvStream {
+    stream: quinn::RecvStream,
+    /// Bytes read before the stream was handed over, returned first.
+    replay: Bytes,
+}
+
+impl RecvStream {
+    fn new(stream: quinn::RecvStream, replay: Bytes) -> Self {
+        RecvStream { stream, replay }
+    }
+}
+
+impl quic::RecvStream for RecvStream {
+    type Buf = Bytes;
+    type Error = QuicError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, QuicError>> {
+        if !self.replay.is_empty() {
+            return Poll::Ready(Ok(Some(std::mem::take(&mut self.replay))));
+        }
+
+        let mut buf = [0u8; READ_CHUNK];
+        let read = ready!(self.stream.poll_read(cx, &mut buf))?;
+        if read == 0 {
+            return Poll::Ready(Ok(None));
+        }
+
+        Poll::Ready(Ok(Some(Bytes::copy_from_slice(&buf[..read]))))
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        let _ = self.stream.stop(VarInt::from_u64(error_code).unwrap_or(VarInt::MAX));
+    }
+
+    fn recv_id(&self) -> StreamId {
+        stream_id(self.stream.id())
+    }
+}
+
+fn stream_id(id: quinn::StreamId) -> StreamId {
+    StreamId::try_from(u64::from(id)).expect("QUIC stream IDs are always valid h3 stream IDs")
+}
+
+/// Errors from the QUIC layer, in the form h3 expects them.
+#[derive(Debug)]
+pub enum QuicError {
+    Connection(quinn::ConnectionError),
+    Read(quinn::ReadError),
+    Write(quinn::WriteError),
+    Closed(quinn::ClosedStream),
+    /// h3 sent more data before the previous write finished.
+    NotReady,
+}
+
+impl QuicError {
+    fn connection_error(&self) -> Option<&quinn::ConnectionError> {
+        match self {
+            QuicError::Connection(e)
+            | QuicError::Read(quinn::ReadError::ConnectionLost(e))
+            | QuicError::Write(quinn::WriteError::ConnectionLost(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl quic::Error for QuicError {
+    fn is_timeout(&self) -> bool {
+        matches!(self.connection_error(), Some(quinn::ConnectionError::TimedOut))
+    }
+
+    fn err_code(&self) -> Option<u64> {
+        match self {
+            QuicError::Read(quinn::ReadError::Reset(code)) => Some(code.into_inner()),
+            QuicError::Write(quinn::WriteError::Stopped(code)) => Some(code.into_inner()),
+            _ => match self.connection_error() {
+                Some(quinn::ConnectionError::ApplicationClosed(close)) => Some(close.error_code.into_inner()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::Connection(e) => e.fmt(f),
+            QuicError::Read(e) => e.fmt(f),
+            QuicError::Write(e) => e.fmt(f),
+            QuicError::Closed(e) => e.fmt(f),
+            QuicError::NotReady => f.write_str("previous write has not finished"),
+        }
+    }
+}
+
+impl std::error::Error for QuicError {}
+
+impl From<quinn::ConnectionError> for QuicError {
+    fn from(e: quinn::ConnectionError) -> Self {
+        QuicError::Connection(e)
+    }
+}
+
+impl From<quinn::ReadError> for QuicError {
+    fn from(e: quinn::ReadError) -> Self {
+        QuicError::Read(e)
+    }
+}
+
+impl From<quinn::WriteError> for QuicError {
+    fn from(e: quinn::WriteError) -> Self {
+        QuicError::Write(e)
+    }
+}
+
+impl From<quinn::ClosedStream> for QuicError {
+    fn from(e: quinn::ClosedStream) -> Self {
+        QuicError::Closed(e)
+    }
+}
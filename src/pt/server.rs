@@ -1,64 +1,500 @@
-use super::env::ServerEnv;
+use super::bridge::BridgeParams;
+use super::decoy::DecoySite;
+use super::env::{OutboundBind, ServerEnv};
+use super::extorport::ExtOrPort;
+use super::header::{StreamHeader, StreamKind, StreamStatus, STREAM_PROTOCOL_VERSION};
+use super::replay::{PartlyRead, RecordingReader, ReplayConnection};
+use super::transport::{self, Transport};
+use crate::acme::AcmeManager;
+use crate::auth::AuthSecret;
+use crate::config::{PaddingProfile, ServerOptions};
+use crate::identity::ServerIdentity;
+use crate::shutdown::{Shutdown, DRAIN_TIMEOUT, SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON};
 use quinn::Endpoint;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncRead;
 
-pub async fn run_server() -> anyhow::Result<()> {
+/// How long a client may take to open its first stream or send a stream's header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Close code for connections that fail authentication and cannot be shown
+/// the decoy site. Zero with no reason, so a prober learns nothing it would
+/// not from an ordinary server.
+const AUTH_FAILED_CLOSE_CODE: quinn::VarInt = quinn::VarInt::from_u32(0);
+
+/// Where relayed streams are sent: the Extended ORPort when Tor offers one,
+/// so it learns each client's address and transport, else the plain ORPort.
+#[derive(Debug, Clone)]
+enum OrPort {
+    Plain(SocketAddr, OutboundBind),
+    Extended(Arc<ExtOrPort>),
+}
+
+impl OrPort {
+    fn from_env(env: &ServerEnv) -> anyhow::Result<Self> {
+        match &env.extended_orport {
+            Some((addr, cookie_file)) => Ok(OrPort::Extended(Arc::new(
+                ExtOrPort::new(*addr, cookie_file, env.outbound_bind)?,
+            ))),
+            None => Ok(OrPort::Plain(env.orport, env.outbound_bind)),
+        }
+    }
+
+    async fn connect(&self, client_addr: SocketAddr, transport: &str) -> anyhow::Result<tokio::net::TcpStream> {
+        use anyhow::Context;
+
+        match self {
+            OrPort::Plain(addr, outbound_bind) => outbound_bind.connect_tcp(*addr)
+                .await
+                .context("Failed to connect to ORPort"),
+            OrPort::Extended(ext_orport) => ext_orport.connect(client_addr, transport).await,
+        }
+    }
+}
+
+/// What a transport's connections need to check and relay their streams.
+#[derive(Debug, Clone)]
+struct Relay {
+    transport: &'static str,
+    auth: AuthSecret,
+    padding: PaddingProfile,
+    orport: OrPort,
+    decoy: Arc<DecoySite>,
+}
+
+/// A connection whose first stream did not prove knowledge of the secret.
+struct Unauthenticated {
+    /// The first stream, if one was opened, with what was read of it.
+    stream: Option<PartlyRead>,
+    reason: anyhow::Error,
+}
+
+/// Runs the server with the configuration Tor passes in `TOR_PT_*` variables.
+pub async fn run_server(shutdown: Shutdown) -> anyhow::Result<()> {
+    use super::env_error;
+
+    let env = match ServerEnv::from_env() {
+        Ok(env) => env,
+        Err(e) => {
+            env_error(&format!("{:#}", e))?;
+            return Err(e.context("Failed to load server environment"));
+        }
+    };
+
+    run_server_with_env(env, shutdown).await
+}
+
+pub async fn run_server_with_env(env: ServerEnv, shutdown: Shutdown) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use super::{env_error, smethod_error, smethods_done};
+
+    let orport = match OrPort::from_env(&env) {
+        Ok(orport) => orport,
+        Err(e) => {
+            env_error(&format!("{:#}", e))?;
+            return Err(e.context("Failed to set up Extended ORPort"));
+        }
+    };
+
+    let (transports, unknown) = transport::select(&env.transports);
+    for name in &unknown {
+        smethod_error(name, "no such transport is supported")?;
+    }
+
+    let mut configured = Vec::new();
+    for transport in transports {
+        match server_options(transport, &env) {
+            Ok(options) => configured.push((transport, options)),
+            Err(e) => smethod_error(
+                transport.name,
+                &format!("Invalid ServerTransportOptions: {:#}", e),
+            )?,
+        }
+    }
+
+    let mut acme = match acme_options(&configured) {
+        Ok(acme) => acme.cloned(),
+        Err(e) => {
+            for (transport, _) in &configured {
+                smethod_error(transport.name, &format!("{:#}", e))?;
+            }
+            configured.clear();
+            None
+        }
+    };
+
+    // All transports share one identity, so a bridge has a single fingerprint.
+    let identity = match configured.first() {
+        Some((_, options)) => match load_identity(&env, options) {
+            Ok(loaded) => Some(loaded),
+            Err(e) => {
+                for (transport, _) in &configured {
+                    smethod_error(transport.name, &format!("{:#}", e))?;
+                }
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Public CAs do not sign Ed25519 keys, whatever cert-key asks for now.
+    let ed25519 = identity.as_ref()
+        .is_some_and(|(identity, _)| identity.key_algorithm() == Some(rustls::SignatureAlgorithm::ED25519));
+    if acme.is_some() && ed25519 {
+        for (transport, options) in &configured {
+            if options.acme.enabled() {
+                smethod_error(
+                    transport.name,
+                    "Server option acme cannot be used with an Ed25519 server key, which public CAs do not sign",
+                )?;
+            }
+        }
+        configured.retain(|(_, options)| !options.acme.enabled());
+        acme = None;
+    }
+
+    // Transports with ACME on serve the CA's certificate instead. It is for
+    // the same key, so their fingerprint is the same.
+    let certified = match (&identity, &acme) {
+        (Some((identity, _)), Some(acme)) => acme_manager(&env, acme).stored_identity(identity),
+        _ => None,
+    };
+    if certified.is_some() {
+        tracing::info!("Using stored ACME certificate");
+    }
+
+    let mut endpoints = Vec::new();
+    // Endpoints of transports with ACME on, with the options they were
+    // configured with, to reconfigure when the certificate is renewed.
+    let mut reconfigurable = Vec::new();
+    if let Some((identity, auth)) = &identity {
+        for (transport, options) in &configured {
+            let served = match &certified {
+                Some(certified) if options.acme.enabled() => certified,
+                _ => identity,
+            };
+            let relay = Relay {
+                transport: transport.name,
+                auth: *auth,
+                padding: options.padding,
+                orport: orport.clone(),
+                decoy: Arc::new(options.decoy.clone()),
+            };
+            match start_transport(transport, options, served, *auth, &env) {
+                Ok(started) => {
+                    if options.acme.enabled() {
+                        reconfigurable.extend(started.iter().map(|e| (e.clone(), options.clone())));
+                    }
+                    endpoints.extend(started.into_iter().map(|e| (relay.clone(), e)));
+                }
+                Err(e) => smethod_error(transport.name, &format!("{:#}", e))?,
+            }
+        }
+    }
+    smethods_done()?;
+
+    if endpoints.is_empty() {
+        anyhow::bail!("None of the requested transports could be started");
+    }
+
+    if let (Some(acme), Some((identity, _))) = (&acme, identity) {
+        spawn_acme_renewal(&env, acme, identity, reconfigurable, shutdown.clone());
+    }
+
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for (relay, endpoint) in &endpoints {
+        accept_loops.spawn(accept_connections(
+            endpoint.clone(),
+            relay.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    while let Some(result) = accept_loops.join_next().await {
+        result.context("Accept loop panicked")?;
+    }
+
+    if !shutdown.drain(DRAIN_TIMEOUT).await {
+        tracing::warn!("Streams still open after {:?}, closing connections", DRAIN_TIMEOUT);
+    }
+
+    for (_, endpoint) in &endpoints {
+        endpoint.close(SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON);
+    }
+    for (_, endpoint) in &endpoints {
+        endpoint.wait_idle().await;
+    }
+
+    Ok(())
+}
+
+/// The transport's defaults overridden by its `ServerTransportOptions`.
+pub fn server_options(transport: &Transport, env: &ServerEnv) -> anyhow::Result<ServerOptions> {
+    match env.transport_options.get(transport.name) {
+        Some(args) => transport.server_options().with_args(args),
+        None => Ok(transport.server_options()),
+    }
+}
+
+/// Loads the certificate identity and the auth secret, which together make
+/// up what a bridge line pins.
+fn load_identity(env: &ServerEnv, options: &ServerOptions) -> anyhow::Result<(ServerIdentity, AuthSecret)> {
     use anyhow::Context;
-    use super::{write_pt_message, PT_VERSION};
 
-    let env = ServerEnv::from_env()
-        .context("Failed to load server environment")?;
+    let state_dir = std::path::Path::new(&env.state_location);
+    // Rotating on every start would break every bridge line handed out, so
+    // it is left to `keygen --force`.
+    if std::env::var_os("QUICTOR_ROTATE_IDENTITY").is_some() {
+        tracing::warn!("Ignoring QUICTOR_ROTATE_IDENTITY; run `quictor-pt keygen --force` to rotate the identity");
+    }
+
+    let identity = match (&options.cert_file, &options.key_file) {
+        (Some(cert_file), Some(key_file)) => ServerIdentity::from_files(cert_file, key_file),
+        _ => {
+            let existing = ServerIdentity::exists(state_dir);
+            let identity = ServerIdentity::load_or_create(state_dir, &options.names_for_new_identity(), &options.certificate);
+            if let (Ok(identity), true) = (&identity, existing) {
+                let mismatches = identity.profile_mismatches(&options.certificate);
+                if !mismatches.is_empty() {
+                    tracing::warn!(
+                        "The stored certificate does not match {}; these options apply only to new certificates, run `quictor-pt keygen --force` to replace it",
+                        mismatches.join(", ")
+                    );
+                }
+            }
+            identity
+        }
+    };
+    let auth = AuthSecret::load_or_create(state_dir);
+    let identity = identity.context("Failed to load server identity")?;
+    let auth = auth.context("Failed to load auth secret")?;
+
+    tracing::info!("Server certificate fingerprint: cert={}", identity.fingerprint());
+
+    Ok((identity, auth))
+}
+
+/// The options of the transport that turns ACME on, if any. Transports share
+/// one identity, so they cannot ask for different ACME certificates, nor have
+/// it replaced by an operator's certificate.
+fn acme_options<'a>(configured: &'a [(&Transport, ServerOptions)]) -> anyhow::Result<Option<&'a ServerOptions>> {
+    let mut acme: Option<(&Transport, &ServerOptions)> = None;
+    for (transport, options) in configured {
+        if !options.acme.enabled() {
+            continue;
+        }
+        match acme {
+            Some((first, chosen)) if chosen.acme != options.acme || chosen.server_names != options.server_names => {
+                anyhow::bail!(
+                    "Transports {} and {} have conflicting acme or sni options",
+                    first.name,
+                    transport.name
+                );
+            }
+            Some(_) => {}
+            None => acme = Some((transport, options)),
+        }
+    }
 
-    let server_config = crate::config::configure_server()
+    let Some((acme_transport, acme)) = acme else {
+        return Ok(None);
+    };
+    if let Some((transport, _)) = configured.iter().find(|(_, options)| options.cert_file.is_some()) {
+        anyhow::bail!(
+            "Transport {} uses acme, which cannot be combined with cert-file on transport {}",
+            acme_transport.name,
+            transport.name
+        );
+    }
+
+    Ok(Some(acme))
+}
+
+fn acme_manager(env: &ServerEnv, options: &ServerOptions) -> AcmeManager {
+    let state_dir = std::path::Path::new(&env.state_location);
+    AcmeManager::new(&options.acme, state_dir, &options.server_names)
+}
+
+/// Keeps the ACME certificate renewed, swapping each new one into `endpoints`.
+/// Connections already established keep the certificate they started with.
+fn spawn_acme_renewal(
+    env: &ServerEnv,
+    options: &ServerOptions,
+    identity: ServerIdentity,
+    endpoints: Vec<(Endpoint, ServerOptions)>,
+    shutdown: Shutdown,
+) {
+    let manager = acme_manager(env, options);
+
+    tokio::spawn(async move {
+        let renewal = manager.run(identity, |identity| {
+            for (endpoint, options) in &endpoints {
+                match crate::config::configure_server(identity, options) {
+                    Ok(server_config) => endpoint.set_server_config(Some(server_config)),
+                    Err(e) => tracing::error!("Failed to install ACME certificate: {:#}", e),
+                }
+            }
+        });
+
+        tokio::select! {
+            _ = renewal => {}
+            _ = shutdown.triggered() => {}
+        }
+    });
+}
+
+/// Binds every address configured for `transport` and announces each with
+/// `SMETHOD`. Individual bind failures are reported with `SMETHOD-ERROR`.
+fn start_transport(
+    transport: &Transport,
+    options: &ServerOptions,
+    identity: &ServerIdentity,
+    auth: AuthSecret,
+    env: &ServerEnv,
+) -> anyhow::Result<Vec<Endpoint>> {
+    use anyhow::Context;
+    use super::{smethod, smethod_error};
+
+    let server_config = crate::config::configure_server(identity, options)
         .context("Failed to configure QUIC server")?;
 
-    let bind_addr = env.bind_addrs.get("quictor")
-        .context("No bind address for 'quictor' transport")?;
+    let smethod_args = BridgeParams::for_server(identity, auth, options).to_args();
 
-    let endpoint = Endpoint::server(server_config, *bind_addr)
-        .context("Failed to create QUIC endpoint")?;
+    let bind_addrs = env.bind_addrs_for(transport.name);
+    if bind_addrs.is_empty() {
+        anyhow::bail!("No bind address for '{}' in TOR_PT_SERVER_BINDADDR", transport.name);
+    }
 
-    let orport = env.orport;
+    let mut endpoints = Vec::new();
+    for bind_addr in bind_addrs {
+        match bind_endpoint(server_config.clone(), bind_addr) {
+            Ok(endpoint) => {
+                smethod(transport.name, endpoint.local_addr()?, &smethod_args)?;
+                endpoints.push(endpoint);
+            }
+            Err(e) => {
+                tracing::error!("Failed to create QUIC endpoint on {}: {}", bind_addr, e);
+                smethod_error(transport.name, &format!("Failed to bind {}: {}", bind_addr, e))?;
+            }
+        }
+    }
+
+    Ok(endpoints)
+}
 
-    write_pt_message(&format!("VERSION {}", PT_VERSION))?;
-    write_pt_message(&format!("SMETHOD quictor {}", bind_addr))?;
-    write_pt_message("SMETHODS DONE")?;
+/// Binds a server endpoint on `addr`. IPv6 sockets are made IPv6-only: Linux
+/// would otherwise make `[::]` dual-stack, so that it and `0.0.0.0` could not
+/// both be bound on the same port.
+fn bind_endpoint(server_config: quinn::ServerConfig, addr: SocketAddr) -> std::io::Result<Endpoint> {
+    use socket2::{Domain, Protocol, Socket, Type};
 
+    let socket = match addr {
+        SocketAddr::V4(_) => std::net::UdpSocket::bind(addr)?,
+        SocketAddr::V6(_) => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_only_v6(true)?;
+            socket.bind(&addr.into())?;
+            socket.into()
+        }
+    };
+    let runtime = quinn::default_runtime()
+        .ok_or_else(|| std::io::Error::other("No async runtime found"))?;
+
+    Endpoint::new(quinn::EndpointConfig::default(), Some(server_config), socket, runtime)
+}
+
+async fn accept_connections(
+    endpoint: Endpoint,
+    relay: Relay,
+    shutdown: Shutdown,
+) {
     loop {
-        let incoming = match endpoint.accept().await {
-            Some(incoming) => incoming,
-            None => {
-                tracing::warn!("Endpoint closed");
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => {
+                    tracing::warn!("Endpoint closed");
+                    break;
+                }
+            },
+            _ = shutdown.triggered() => {
+                tracing::debug!("No longer accepting connections on {:?}", endpoint.local_addr());
                 break;
             }
         };
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(incoming, orport).await {
+        let relay = relay.clone();
+        let shutdown_clone = shutdown.clone();
+        shutdown.spawn(async move {
+            if let Err(e) = handle_connection(incoming, relay, shutdown_clone).await {
                 tracing::error!("Failed to handle connection: {}", e);
             }
         });
     }
-
-    Ok(())
 }
 
 async fn handle_connection(
     incoming: quinn::Incoming,
-    orport: SocketAddr,
+    relay: Relay,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     use anyhow::Context;
 
+    // Resumed clients send their first stream as 0-RTT data, which an
+    // attacker can replay. Waiting for the full handshake keeps replays away
+    // from the ORPort: streams are only relayed once the client has proven it
+    // holds the session keys, and the ticket it resumed is already used up.
     let connection = incoming.await
         .context("Failed to accept QUIC connection")?;
 
-    tracing::info!("New QUIC connection from {}", connection.remote_address());
+    let client_addr = connection.remote_address();
+    tracing::info!("New QUIC connection from {}", client_addr);
+
+    // Nothing is relayed until the first stream proves the client knows the
+    // bridge's secret. Anyone else gets the decoy website if the connection
+    // speaks HTTP/3, and is disconnected without an answer otherwise.
+    let first = tokio::select! {
+        first = authenticate(&connection, &relay) => first,
+        _ = shutdown.triggered() => return Ok(()),
+    };
+    let (send, recv, header) = match first {
+        Ok(first) => first,
+        Err(rejected) if negotiated_h3(&connection) => {
+            tracing::info!(
+                "Serving decoy site to unauthenticated connection from {}: {:#}",
+                client_addr,
+                rejected.reason
+            );
+            let connection = ReplayConnection::new(connection, rejected.stream);
+            tokio::select! {
+                result = super::decoy::serve(connection, &relay.decoy, &shutdown) => result?,
+                _ = shutdown.triggered() => {}
+            }
+            return Ok(());
+        }
+        Err(rejected) => {
+            tracing::info!("Closing unauthenticated connection from {}: {:#}", client_addr, rejected.reason);
+            connection.close(AUTH_FAILED_CLOSE_CODE, b"");
+            return Ok(());
+        }
+    };
+    spawn_stream(&shutdown, send, recv, Some(header), client_addr, &relay);
 
     loop {
         tracing::debug!("Waiting for bidirectional stream...");
-        let stream = match connection.accept_bi().await {
+        let accepted = tokio::select! {
+            accepted = connection.accept_bi() => accepted,
+            _ = shutdown.triggered() => break,
+        };
+        let (send, recv) = match accepted {
             Ok(stream) => {
-                tracing::info!("Accepted bidirectional stream");
+                if stream.1.is_0rtt() {
+                    tracing::debug!("Accepted bidirectional stream opened with 0-RTT");
+                } else {
+                    tracing::info!("Accepted bidirectional stream");
+                }
                 stream
             }
             Err(quinn::ConnectionError::ApplicationClosed(_)) => {
@@ -75,30 +511,137 @@ async fn handle_connection(
             }
         };
 
-        let (send, recv) = stream;
-
-        tokio::spawn(async move {
-            if let Err(e) = handle_stream(send, recv, orport).await {
-                tracing::error!("Failed to handle stream: {}", e);
-            }
-        });
+        spawn_stream(&shutdown, send, recv, None, client_addr, &relay);
     }
 
     Ok(())
 }
 
+/// Waits for the connection's first stream and checks the token in its header.
+/// What was read is kept on failure, since the stream may be an HTTP/3 request.
+async fn authenticate(
+    connection: &quinn::Connection,
+    relay: &Relay,
+) -> Result<(quinn::SendStream, quinn::RecvStream, StreamHeader), Unauthenticated> {
+    let (send, recv) = match tokio::time::timeout(HEADER_TIMEOUT, connection.accept_bi()).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(Unauthenticated {
+            stream: None,
+            reason: e.into(),
+        }),
+        Err(_) => return Err(Unauthenticated {
+            stream: None,
+            reason: anyhow::anyhow!("No stream opened"),
+        }),
+    };
+
+    let mut reader = RecordingReader::new(recv);
+    let reason = match read_header(&mut reader).await {
+        Ok(header) if is_authorized(&header, &relay.auth) => {
+            return Ok((send, reader.into_inner(), header));
+        }
+        Ok(_) => anyhow::anyhow!("Missing or invalid auth token"),
+        Err(e) => e,
+    };
+
+    Err(Unauthenticated {
+        stream: Some(reader.into_partly_read(send)),
+        reason,
+    })
+}
+
+/// Whether the client picked HTTP/3 as the connection's ALPN protocol.
+fn negotiated_h3(connection: &quinn::Connection) -> bool {
+    connection.handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .is_some_and(|protocol| protocol == b"h3")
+}
+
+fn is_authorized(header: &StreamHeader, auth: &AuthSecret) -> bool {
+    header.token.as_deref().is_some_and(|token| auth.verify(token))
+}
+
+async fn read_header<R: AsyncRead + Unpin>(quic_recv: &mut R) -> anyhow::Result<StreamHeader> {
+    use anyhow::Context;
+
+    tokio::time::timeout(HEADER_TIMEOUT, StreamHeader::read(quic_recv))
+        .await
+        .context("Timed out waiting for the stream header")?
+        .context("Failed to read the stream header")
+}
+
+/// Relays a stream in the background. `header` is given when it has already
+/// been read, as for the stream that authenticated the connection.
+fn spawn_stream(
+    shutdown: &Shutdown,
+    send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    header: Option<StreamHeader>,
+    client_addr: SocketAddr,
+    relay: &Relay,
+) {
+    let relay = relay.clone();
+
+    shutdown.spawn(async move {
+        let header = match header {
+            Some(header) => header,
+            None => match read_header(&mut recv).await {
+                Ok(header) => header,
+                Err(e) => {
+                    tracing::error!("Failed to handle stream: {:#}", e);
+                    return;
+                }
+            },
+        };
+        if let Err(e) = handle_stream(send, recv, header, client_addr, &relay).await {
+            tracing::error!("Failed to handle stream: {}", e);
+        }
+    });
+}
+
+/// Answers a stream's header and, for an accepted relay stream, copies data
+/// between the stream and the ORPort.
 async fn handle_stream(
-    quic_send: quinn::SendStream,
+    mut quic_send: quinn::SendStream,
     quic_recv: quinn::RecvStream,
-    orport: SocketAddr,
+    header: StreamHeader,
+    client_addr: SocketAddr,
+    relay: &Relay,
 ) -> anyhow::Result<()> {
     use anyhow::Context;
 
-    let mut tcp_stream = tokio::net::TcpStream::connect(orport)
-        .await
-        .context("Failed to connect to ORPort")?;
+    let rejection = if header.version != STREAM_PROTOCOL_VERSION {
+        Some(StreamStatus::UnsupportedVersion)
+    } else if !is_authorized(&header, &relay.auth) {
+        Some(StreamStatus::Unauthorized)
+    } else if header.kind != Some(StreamKind::Relay) {
+        Some(StreamStatus::UnsupportedKind)
+    } else {
+        None
+    };
+    if let Some(status) = rejection {
+        reject_stream(&mut quic_send, status, relay.padding).await;
+        anyhow::bail!("Rejected stream from {}: {}", client_addr, status);
+    }
+
+    let mut tcp_stream = match relay.orport.connect(client_addr, relay.transport).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            // Tor's log is where bridge operators look when users can't connect.
+            let message = format!("{}: cannot relay to Tor: {:#}", relay.transport, e);
+            if let Err(e) = super::log(super::LogSeverity::Warning, &message) {
+                tracing::warn!("Failed to send LOG to Tor: {}", e);
+            }
+            reject_stream(&mut quic_send, StreamStatus::Unavailable, relay.padding).await;
+            return Err(e);
+        }
+    };
 
-    tracing::debug!("Connected to ORPort at {}", orport);
+    tracing::debug!("Connected to ORPort for {}", client_addr);
+
+    StreamStatus::Accepted.write(&mut quic_send, relay.padding).await
+        .context("Failed to accept stream")?;
 
     let mut quic_stream = tokio::io::join(quic_recv, quic_send);
 
@@ -113,3 +656,33 @@ async fn handle_stream(
 
     Ok(())
 }
+
+async fn reject_stream(quic_send: &mut quinn::SendStream, status: StreamStatus, padding: PaddingProfile) {
+    if let Err(e) = status.write(quic_send, padding).await {
+        tracing::debug!("Failed to send stream status: {:#}", e);
+    }
+    let _ = quic_send.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate::CertProfile;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn server_config() -> quinn::ServerConfig {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let identity = ServerIdentity::ephemeral(&["localhost".to_string()], &CertProfile::default()).unwrap();
+        crate::config::configure_server(&identity, &ServerOptions::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn binds_both_wildcards_on_one_port() {
+        let v4 = bind_endpoint(server_config(), SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).unwrap();
+        let port = v4.local_addr().unwrap().port();
+
+        let v6 = bind_endpoint(server_config(), SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).unwrap();
+
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+}
@@ -0,0 +1,41 @@
+use super::bridge::BridgeParams;
+use quinn::ClientConfig;
+use rustls::client::ClientSessionMemoryCache;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Tickets kept per bridge. rustls holds at most 8 per server name and sizes
+/// its table in whole names, so this leaves room for a few `sni=` values.
+const TICKETS_PER_BRIDGE: usize = 32;
+
+/// QUIC client configs per bridge, each with its own TLS session ticket
+/// store, so reconnects can resume and send their first bytes as 0-RTT data.
+///
+/// rustls files tickets under the server name, and many bridges share one
+/// SNI, so a shared store could offer one bridge's ticket to another. It also
+/// only resumes with the exact verifier that accepted the original session,
+/// which is why the whole config is kept rather than just the store.
+#[derive(Debug, Clone, Default)]
+pub struct SessionCache {
+    configs: Arc<Mutex<HashMap<BridgeParams, ClientConfig>>>,
+}
+
+impl SessionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The client config for the bridge with `params`, built on first use.
+    pub fn client_config(&self, params: &BridgeParams) -> anyhow::Result<ClientConfig> {
+        let mut configs = self.configs.lock().unwrap();
+        if let Some(config) = configs.get(params) {
+            return Ok(config.clone());
+        }
+
+        let session_store = Arc::new(ClientSessionMemoryCache::new(TICKETS_PER_BRIDGE));
+        let config = crate::config::configure_client(params, session_store)?;
+        configs.insert(params.clone(), config.clone());
+
+        Ok(config)
+    }
+}
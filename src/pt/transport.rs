@@ -0,0 +1,68 @@
+use crate::config::ServerOptions;
+
+/// A named flavour of the QUIC transport that Tor can ask us to launch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transport {
+    pub name: &'static str,
+    /// ALPN protocols used when neither the bridge line nor the server options set any.
+    pub alpn: &'static [&'static str],
+}
+
+/// Transport used when none is named, for instance by standalone mode.
+pub const DEFAULT_TRANSPORT: &str = "quictor_h3";
+
+/// Every transport this binary can run, in the order they are launched.
+pub const TRANSPORTS: &[Transport] = &[
+    // Looks like HTTP/3 to an observer of the handshake, and to a prober,
+    // who is shown the decoy site.
+    Transport {
+        name: "quictor_h3",
+        alpn: &["h3"],
+    },
+    // Plain QUIC with no ALPN, kept for existing bridge lines. Probers are
+    // disconnected rather than shown the decoy site.
+    Transport {
+        name: "quictor",
+        alpn: &[],
+    },
+];
+
+impl Transport {
+    pub fn lookup(name: &str) -> Option<&'static Transport> {
+        TRANSPORTS.iter().find(|t| t.name == name)
+    }
+
+    pub fn default_alpn(&self) -> Vec<String> {
+        self.alpn.iter().map(|p| p.to_string()).collect()
+    }
+
+    pub fn server_options(&self) -> ServerOptions {
+        ServerOptions {
+            alpn: self.default_alpn(),
+            ..ServerOptions::default()
+        }
+    }
+}
+
+/// Resolves the names Tor requested into known transports.
+///
+/// Returns the transports to launch and the names we don't implement, which
+/// the caller reports with `CMETHOD-ERROR`/`SMETHOD-ERROR`. `*` requests all.
+pub fn select(requested: &[String]) -> (Vec<&'static Transport>, Vec<String>) {
+    if requested.iter().any(|name| name == "*") {
+        return (TRANSPORTS.iter().collect(), Vec::new());
+    }
+
+    let mut selected = Vec::new();
+    let mut unknown = Vec::new();
+
+    for name in requested {
+        match Transport::lookup(name) {
+            Some(transport) if !selected.contains(&transport) => selected.push(transport),
+            Some(_) => {}
+            None => unknown.push(name.clone()),
+        }
+    }
+
+    (selected, unknown)
+}
@@ -0,0 +1,115 @@
+use quinn::VarInt;
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Application close code sent to peers when we shut down deliberately.
+pub const SHUTDOWN_CLOSE_CODE: VarInt = VarInt::from_u32(0);
+pub const SHUTDOWN_CLOSE_REASON: &[u8] = b"shutdown";
+
+/// How long in-flight streams get to finish once shutdown starts.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Coordinates a graceful exit across accept loops and connection tasks.
+///
+/// Accept loops watch [`Shutdown::triggered`] to stop taking new work, and
+/// per-connection work is spawned through [`Shutdown::spawn`] so that
+/// [`Shutdown::drain`] can wait for it.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Spawns a task that [`Shutdown::drain`] will wait for.
+    pub fn spawn<F>(&self, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(future)
+    }
+
+    /// Waits up to `timeout` for tracked tasks to finish.
+    ///
+    /// Returns `false` if some were still running when the deadline passed.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tracker.close();
+        tokio::time::timeout(timeout, self.tracker.wait()).await.is_ok()
+    }
+
+    /// Triggers shutdown on SIGTERM or SIGINT, and on stdin EOF when Tor sets
+    /// `TOR_PT_EXIT_ON_STDIN_CLOSE=1`.
+    pub fn watch_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let reason = wait_for_signal().await;
+            tracing::info!("Received {}, shutting down", reason);
+            shutdown.trigger();
+        });
+
+        if std::env::var("TOR_PT_EXIT_ON_STDIN_CLOSE").as_deref() == Ok("1") {
+            // A plain thread rather than tokio::io::stdin: its blocking read
+            // cannot be cancelled and would keep the runtime from shutting down.
+            let shutdown = self.clone();
+            std::thread::spawn(move || {
+                wait_for_stdin_close();
+                tracing::info!("Tor closed stdin, shutting down");
+                shutdown.trigger();
+            });
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!("Failed to install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+fn wait_for_stdin_close() {
+    use std::io::Read;
+
+    let mut stdin = std::io::stdin();
+    let mut buf = [0u8; 256];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
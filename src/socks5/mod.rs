@@ -1,3 +1,6 @@
+pub mod udp;
+
+use crate::pt::args::PtArgs;
 use tokio::net::{TcpListener, TcpStream};
 use std::net::SocketAddr;
 
@@ -25,10 +28,13 @@ impl Socks5Server {
 
 pub struct Socks5Connection {
     stream: TcpStream,
-    target_addr: SocketAddr,
+    target: String,
+    args: PtArgs,
 }
 
 impl Socks5Connection {
+    /// Reads the client's CONNECT request, leaving the reply to `accept` or
+    /// `reject` once the caller knows whether it can serve it.
     pub async fn handshake(mut stream: TcpStream) -> anyhow::Result<Self> {
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
         use anyhow::{Context, bail};
@@ -46,8 +52,19 @@ impl Socks5Connection {
         stream.read_exact(&mut methods).await
             .context("Failed to read auth methods")?;
 
-        stream.write_all(&[0x05, 0x00]).await
-            .context("Failed to write auth response")?;
+        let args = if methods.contains(&0x02) {
+            stream.write_all(&[0x05, 0x02]).await
+                .context("Failed to write auth response")?;
+            Self::read_pt_args(&mut stream).await?
+        } else if methods.contains(&0x00) {
+            stream.write_all(&[0x05, 0x00]).await
+                .context("Failed to write auth response")?;
+            PtArgs::new()
+        } else {
+            stream.write_all(&[0x05, 0xff]).await
+                .context("Failed to write auth response")?;
+            bail!("No acceptable SOCKS authentication method offered");
+        };
 
         let mut buf = [0u8; 4];
         stream.read_exact(&mut buf).await
@@ -61,7 +78,7 @@ impl Socks5Connection {
         }
 
         let atyp = buf[3];
-        let target_addr = match atyp {
+        let target = match atyp {
             0x01 => {
                 let mut addr = [0u8; 4];
                 stream.read_exact(&mut addr).await
@@ -71,7 +88,7 @@ impl Socks5Connection {
                     .context("Failed to read port")?;
                 let port = u16::from_be_bytes(port_buf);
 
-                SocketAddr::from((addr, port))
+                SocketAddr::from((addr, port)).to_string()
             }
             0x03 => {
                 let mut len_buf = [0u8; 1];
@@ -91,13 +108,7 @@ impl Socks5Connection {
                 let domain_str = String::from_utf8(domain)
                     .context("Invalid UTF-8 in domain name")?;
 
-                let addr = tokio::net::lookup_host(format!("{}:{}", domain_str, port))
-                    .await
-                    .context("Failed to resolve domain")?
-                    .next()
-                    .context("No addresses found for domain")?;
-
-                addr
+                format!("{}:{}", domain_str, port)
             }
             0x04 => {
                 let mut addr = [0u8; 16];
@@ -108,49 +119,132 @@ impl Socks5Connection {
                     .context("Failed to read port")?;
                 let port = u16::from_be_bytes(port_buf);
 
-                SocketAddr::from((addr, port))
+                SocketAddr::from((addr, port)).to_string()
             }
             _ => bail!("Unsupported address type: {}", atyp),
         };
 
+        Ok(Socks5Connection {
+            stream,
+            target,
+            args,
+        })
+    }
+
+    /// Tells the client the connection succeeded and hands over the stream.
+    pub async fn accept(mut self) -> anyhow::Result<TcpStream> {
+        use anyhow::Context;
+
+        self.reply(0x00).await
+            .context("Failed to write connection response")?;
+        Ok(self.stream)
+    }
+
+    /// Refuses the connection with SOCKS5 reply code `rep`, such as 0x01
+    /// (general failure).
+    pub async fn reject(mut self, rep: u8) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        self.reply(rep).await
+            .context("Failed to write connection response")
+    }
+
+    async fn reply(&mut self, rep: u8) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
         let response = [
             0x05, // VER
-            0x00, // REP (success)
+            rep,  // REP
             0x00, // RSV
             0x01, // ATYP (IPv4)
             0, 0, 0, 0, // BND.ADDR (0.0.0.0)
             0, 0, // BND.PORT (0)
         ];
-        stream.write_all(&response).await
-            .context("Failed to write connection response")?;
+        self.stream.write_all(&response).await
+    }
 
-        Ok(Socks5Connection {
-            stream,
-            target_addr,
-        })
+    /// Reads an RFC 1929 username/password request and decodes it as PT arguments.
+    ///
+    /// Tor splits the argument string across both fields; when it fits in the
+    /// username, the password is a single NUL byte.
+    async fn read_pt_args(stream: &mut TcpStream) -> anyhow::Result<PtArgs> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use anyhow::{Context, bail};
+
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await
+            .context("Failed to read auth subnegotiation header")?;
+
+        if buf[0] != 0x01 {
+            bail!("Unsupported username/password auth version: {}", buf[0]);
+        }
+
+        let mut username = vec![0u8; buf[1] as usize];
+        stream.read_exact(&mut username).await
+            .context("Failed to read username")?;
+
+        let mut len_buf = [0u8; 1];
+        stream.read_exact(&mut len_buf).await
+            .context("Failed to read password length")?;
+        let mut password = vec![0u8; len_buf[0] as usize];
+        stream.read_exact(&mut password).await
+            .context("Failed to read password")?;
+
+        let mut raw = username;
+        if password != [0x00] {
+            raw.extend_from_slice(&password);
+        }
+
+        let args = String::from_utf8(raw)
+            .context("Invalid UTF-8 in transport arguments")
+            .and_then(|s| PtArgs::parse_socks(&s));
+
+        match args {
+            Ok(args) => {
+                stream.write_all(&[0x01, 0x00]).await
+                    .context("Failed to write auth status")?;
+                Ok(args)
+            }
+            Err(e) => {
+                stream.write_all(&[0x01, 0x01]).await
+                    .context("Failed to write auth status")?;
+                Err(e)
+            }
+        }
     }
 
-    pub fn target_addr(&self) -> SocketAddr {
-        self.target_addr
+    /// The address Tor asked us to connect to, as `host:port`.
+    pub fn target(&self) -> &str {
+        &self.target
     }
 
-    pub fn into_stream(self) -> TcpStream {
-        self.stream
+    /// Per-bridge transport arguments from the SOCKS username/password fields.
+    pub fn args(&self) -> &PtArgs {
+        &self.args
     }
 }
 
-pub async fn connect_via_socks5(
-    proxy_addr: SocketAddr,
-    target_addr: SocketAddr,
-) -> anyhow::Result<TcpStream> {
+/// Username/password for RFC 1929 authentication to an upstream proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Performs the client side of SOCKS5 method selection, authenticating with
+/// `credentials` if the proxy asks for it.
+async fn negotiate_auth(
+    stream: &mut TcpStream,
+    credentials: Option<&ProxyCredentials>,
+) -> anyhow::Result<()> {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use anyhow::{Context, bail};
 
-    let mut stream = TcpStream::connect(proxy_addr)
-        .await
-        .context("Failed to connect to SOCKS5 proxy")?;
-
-    stream.write_all(&[0x05, 0x01, 0x00]).await
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[0x05, 0x02, 0x00, 0x02],
+        None => &[0x05, 0x01, 0x00],
+    };
+    stream.write_all(greeting).await
         .context("Failed to write auth request")?;
 
     let mut buf = [0u8; 2];
@@ -160,27 +254,55 @@ pub async fn connect_via_socks5(
     if buf[0] != 0x05 {
         bail!("Invalid SOCKS version in auth response: {}", buf[0]);
     }
-    if buf[1] != 0x00 {
-        bail!("SOCKS5 proxy requires authentication (method: {})", buf[1]);
-    }
 
-    let mut request = vec![0x05, 0x01, 0x00];
+    match (buf[1], credentials) {
+        (0x00, _) => Ok(()),
+        (0x02, Some(credentials)) => {
+            let username = credentials.username.as_bytes();
+            let password = credentials.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                bail!("SOCKS5 proxy username and password must be at most 255 bytes");
+            }
+
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await
+                .context("Failed to write username/password")?;
+
+            stream.read_exact(&mut buf).await
+                .context("Failed to read username/password status")?;
+            if buf[1] != 0x00 {
+                bail!("SOCKS5 proxy rejected the username/password");
+            }
+            Ok(())
+        }
+        (0xff, _) => bail!("SOCKS5 proxy accepted none of the offered auth methods"),
+        (method, _) => bail!("SOCKS5 proxy requires authentication (method: {})", method),
+    }
+}
 
-    match target_addr {
+/// Appends ATYP, address and port for `addr` in SOCKS5 wire format.
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
         SocketAddr::V4(addr) => {
-            request.push(0x01); // ATYP: IPv4
-            request.extend_from_slice(&addr.ip().octets());
-            request.extend_from_slice(&addr.port().to_be_bytes());
+            buf.push(0x01); // ATYP: IPv4
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
         }
         SocketAddr::V6(addr) => {
-            request.push(0x04); // ATYP: IPv6
-            request.extend_from_slice(&addr.ip().octets());
-            request.extend_from_slice(&addr.port().to_be_bytes());
+            buf.push(0x04); // ATYP: IPv6
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
         }
     }
+}
 
-    stream.write_all(&request).await
-        .context("Failed to write connection request")?;
+/// Reads a SOCKS5 reply and returns its bound address, if it is an IP address.
+async fn read_reply(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    use tokio::io::AsyncReadExt;
+    use anyhow::{Context, bail};
 
     let mut buf = [0u8; 4];
     stream.read_exact(&mut buf).await
@@ -196,11 +318,13 @@ pub async fn connect_via_socks5(
     }
 
     let atyp = buf[3];
-    match atyp {
+    let bound = match atyp {
         0x01 => {
-            let mut discard = [0u8; 6];
-            stream.read_exact(&mut discard).await
+            let mut addr = [0u8; 6];
+            stream.read_exact(&mut addr).await
                 .context("Failed to read IPv4 bind address")?;
+            let ip: [u8; 4] = addr[..4].try_into()?;
+            Some(SocketAddr::from((ip, u16::from_be_bytes([addr[4], addr[5]]))))
         }
         0x03 => {
             let mut len_buf = [0u8; 1];
@@ -210,14 +334,49 @@ pub async fn connect_via_socks5(
             let mut discard = vec![0u8; len + 2];
             stream.read_exact(&mut discard).await
                 .context("Failed to read domain bind address")?;
+            None
         }
         0x04 => {
-            let mut discard = [0u8; 18];
-            stream.read_exact(&mut discard).await
+            let mut addr = [0u8; 18];
+            stream.read_exact(&mut addr).await
                 .context("Failed to read IPv6 bind address")?;
+            let ip: [u8; 16] = addr[..16].try_into()?;
+            Some(SocketAddr::from((ip, u16::from_be_bytes([addr[16], addr[17]]))))
         }
         _ => bail!("Unsupported address type in response: {}", atyp),
-    }
+    };
+
+    Ok(bound)
+}
 
-    Ok(stream)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn refuses_a_request_with_a_failure_reply() {
+        let server = Socks5Server::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+
+        let args = b"cert=bad";
+        let mut request = vec![0x05, 0x01, 0x02, 0x01, args.len() as u8];
+        request.extend_from_slice(args);
+        request.extend_from_slice(&[0x01, 0x00]);
+        request.extend_from_slice(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x01, 0xbb]);
+        client.write_all(&request).await.unwrap();
+
+        let conn = server.accept().await.unwrap();
+        assert_eq!(conn.target(), "192.0.2.1:443");
+        assert_eq!(conn.args().get("cert"), Some("bad"));
+
+        let mut negotiation = [0u8; 4];
+        client.read_exact(&mut negotiation).await.unwrap();
+        assert_eq!(negotiation, [0x05, 0x02, 0x01, 0x00]);
+
+        conn.reject(0x01).await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
 }
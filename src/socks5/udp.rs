@@ -0,0 +1,243 @@
+use super::{encode_addr, negotiate_auth, read_reply, ProxyCredentials};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// A UDP socket whose datagrams travel through a SOCKS5 proxy's UDP ASSOCIATE relay.
+///
+/// Implements [`AsyncUdpSocket`] so a [`quinn::Endpoint`] can run on top of it
+/// unchanged: every outgoing datagram gets the RFC 1928 UDP request header
+/// naming its real destination, and the header is stripped from replies.
+#[derive(Debug)]
+pub struct Socks5UdpSocket {
+    io: UdpSocket,
+    relay: SocketAddr,
+    // The proxy tears down the association when this connection closes.
+    _control: TcpStream,
+}
+
+impl Socks5UdpSocket {
+    pub async fn associate(
+        proxy_addr: SocketAddr,
+        credentials: Option<&ProxyCredentials>,
+    ) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        use tokio::io::AsyncWriteExt;
+
+        let mut control = TcpStream::connect(proxy_addr)
+            .await
+            .context("Failed to connect to SOCKS5 proxy")?;
+
+        negotiate_auth(&mut control, credentials).await?;
+
+        let unspecified = match proxy_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let io = UdpSocket::bind(SocketAddr::new(unspecified, 0))
+            .await
+            .context("Failed to bind local UDP socket")?;
+
+        let mut request = vec![0x05, 0x03, 0x00];
+        encode_addr(&mut request, io.local_addr()?);
+        control.write_all(&request).await
+            .context("Failed to write UDP ASSOCIATE request")?;
+
+        let bound = read_reply(&mut control).await
+            .context("SOCKS5 proxy refused UDP ASSOCIATE")?
+            .context("SOCKS5 proxy returned a domain name as its UDP relay")?;
+
+        // Many proxies answer 0.0.0.0, meaning "the address you connected to".
+        let relay = if bound.ip().is_unspecified() {
+            SocketAddr::new(proxy_addr.ip(), bound.port())
+        } else {
+            bound
+        };
+
+        tracing::info!("Relaying QUIC through SOCKS5 proxy {} (UDP relay {})", proxy_addr, relay);
+
+        Ok(Socks5UdpSocket {
+            io,
+            relay,
+            _control: control,
+        })
+    }
+
+    fn send_datagram(&self, destination: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(22 + payload.len());
+        datagram.extend_from_slice(&[0x00, 0x00, 0x00]); // RSV, FRAG
+        encode_addr(&mut datagram, destination);
+        datagram.extend_from_slice(payload);
+
+        self.io.try_send_to(&datagram, self.relay)?;
+        Ok(())
+    }
+}
+
+impl AsyncUdpSocket for Socks5UdpSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Socks5UdpPoller {
+            socket: self,
+            writable: None,
+        })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        match transmit.segment_size {
+            Some(segment_size) => {
+                for segment in transmit.contents.chunks(segment_size) {
+                    self.send_datagram(transmit.destination, segment)?;
+                }
+                Ok(())
+            }
+            None => self.send_datagram(transmit.destination, transmit.contents),
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let buf: &mut [u8] = &mut bufs[0];
+            let mut read_buf = tokio::io::ReadBuf::new(buf);
+            let from = ready!(self.io.poll_recv_from(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+
+            if from.ip() != self.relay.ip() {
+                tracing::debug!("Dropping datagram from {} that did not come from the relay", from);
+                continue;
+            }
+
+            let Some((source, header_len)) = parse_udp_header(&buf[..len]) else {
+                tracing::debug!("Dropping malformed or fragmented datagram from the relay");
+                continue;
+            };
+
+            buf.copy_within(header_len..len, 0);
+            let payload_len = len - header_len;
+
+            meta[0] = RecvMeta {
+                addr: source,
+                len: payload_len,
+                stride: payload_len,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
+
+type WritableFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send + Sync>>;
+
+/// Each poller owns its own `writable()` future so that several connections
+/// can wait for the socket concurrently without stealing each other's wakeups.
+struct Socks5UdpPoller {
+    socket: Arc<Socks5UdpSocket>,
+    writable: Option<WritableFuture>,
+}
+
+impl fmt::Debug for Socks5UdpPoller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5UdpPoller").finish_non_exhaustive()
+    }
+}
+
+impl UdpPoller for Socks5UdpPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let writable = this.writable.get_or_insert_with(|| {
+            let socket = this.socket.clone();
+            Box::pin(async move { socket.io.writable().await })
+        });
+
+        let result = writable.as_mut().poll(cx);
+        if result.is_ready() {
+            this.writable = None;
+        }
+        result
+    }
+}
+
+/// Parses the RFC 1928 UDP request header, returning the datagram's source and
+/// the header length. Fragments are not supported and yield `None`, as do
+/// domain-name sources, which quinn could not match to a connection.
+fn parse_udp_header(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    if datagram.len() < 4 || datagram[2] != 0x00 {
+        return None;
+    }
+
+    let (ip, addr_len): (IpAddr, usize) = match datagram[3] {
+        0x01 => {
+            let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (octets.into(), 4)
+        }
+        0x04 => {
+            let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (octets.into(), 16)
+        }
+        _ => return None,
+    };
+
+    let port_at = 4 + addr_len;
+    let port = u16::from_be_bytes(datagram.get(port_at..port_at + 2)?.try_into().ok()?);
+
+    Some((SocketAddr::new(ip, port), port_at + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ip_sources() {
+        let v4 = [0x00, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x01, 0xbb, 0xaa];
+        assert_eq!(parse_udp_header(&v4), Some(("192.0.2.1:443".parse().unwrap(), 10)));
+
+        let mut v6 = vec![0x00, 0x00, 0x00, 0x04];
+        v6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&[0x20, 0xfb]);
+        assert_eq!(parse_udp_header(&v6), Some(("[2001:db8::1]:8443".parse().unwrap(), 22)));
+    }
+
+    #[test]
+    fn drops_unsupported_datagrams() {
+        // FRAG set.
+        assert_eq!(parse_udp_header(&[0x00, 0x00, 0x01, 0x01, 192, 0, 2, 1, 0x01, 0xbb]), None);
+        // Domain-name source.
+        let mut domain = vec![0x00, 0x00, 0x00, 0x03, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(parse_udp_header(&domain), None);
+        // Unknown address type.
+        assert_eq!(parse_udp_header(&[0x00, 0x00, 0x00, 0x05, 192, 0, 2, 1, 0x01, 0xbb]), None);
+    }
+
+    #[test]
+    fn drops_truncated_headers() {
+        let v4 = [0x00, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x01, 0xbb];
+        for len in 0..v4.len() {
+            assert_eq!(parse_udp_header(&v4[..len]), None, "{} bytes", len);
+        }
+
+        let v6 = [&[0x00, 0x00, 0x00, 0x04][..], &[0; 16], &[0x01, 0xbb]].concat();
+        for len in 0..v6.len() {
+            assert_eq!(parse_udp_header(&v6[..len]), None, "{} bytes", len);
+        }
+    }
+}
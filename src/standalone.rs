@@ -0,0 +1,203 @@
+use crate::pt::args::PtArgs;
+use crate::pt::env::{ClientEnv, OutboundBind, ServerEnv};
+use crate::pt::transport::DEFAULT_TRANSPORT;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SOCKS_ADDR: &str = "127.0.0.1:1080";
+
+/// Settings for running without Tor, read from a TOML file.
+///
+/// ```toml
+/// [server]
+/// bind = ["0.0.0.0:443"]
+/// forward = "127.0.0.1:9001"
+/// state_dir = "/var/lib/quictor"
+///
+/// [server.options]
+/// idle-timeout = 120
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub client: Option<ClientSection>,
+    pub server: Option<ServerSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSection {
+    pub transport: Option<String>,
+    pub socks_addr: Option<SocketAddr>,
+    pub state_dir: Option<String>,
+    pub proxy: Option<String>,
+    pub outbound_bind_v4: Option<Ipv4Addr>,
+    pub outbound_bind_v6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSection {
+    pub transport: Option<String>,
+    #[serde(default)]
+    pub bind: Vec<SocketAddr>,
+    pub forward: Option<SocketAddr>,
+    pub state_dir: Option<String>,
+    pub outbound_bind_v4: Option<Ipv4Addr>,
+    pub outbound_bind_v6: Option<Ipv6Addr>,
+    /// The same keys as `ServerTransportOptions`.
+    #[serde(default)]
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Failed to read config file {}", path.display()))?;
+
+        toml::from_str(&contents)
+            .context(format!("Invalid config file {}", path.display()))
+    }
+}
+
+/// Command-line flags for a standalone client. They override the config file.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ClientFlags {
+    /// TOML config file with a [client] section
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Transport to run [default: quictor_h3]
+    #[arg(long)]
+    pub transport: Option<String>,
+    /// Address of the SOCKS5 listener [default: 127.0.0.1:1080]
+    #[arg(long)]
+    pub socks_addr: Option<SocketAddr>,
+    /// State directory [default: current directory]
+    #[arg(long)]
+    pub state_dir: Option<String>,
+    /// Upstream proxy URI, e.g. socks5://127.0.0.1:1081
+    #[arg(long)]
+    pub proxy: Option<String>,
+}
+
+/// Command-line flags for a standalone server. They override the config file.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ServerFlags {
+    /// TOML config file with a [server] section
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Transport to run [default: quictor_h3]
+    #[arg(long)]
+    pub transport: Option<String>,
+    /// UDP address to accept QUIC connections on; may be repeated
+    #[arg(long)]
+    pub bind: Vec<SocketAddr>,
+    /// TCP address that streams are forwarded to, usually Tor's ORPort
+    #[arg(long)]
+    pub forward: Option<SocketAddr>,
+    /// State directory holding the server identity
+    #[arg(long)]
+    pub state_dir: Option<String>,
+    /// Server option as KEY=VALUE, like ServerTransportOptions; may be repeated
+    #[arg(long = "option", value_name = "KEY=VALUE")]
+    pub options: Vec<String>,
+}
+
+impl ClientFlags {
+    pub fn into_env(self) -> anyhow::Result<ClientEnv> {
+        let file = match &self.config {
+            Some(path) => ConfigFile::load(path)?.client.unwrap_or_default(),
+            None => ClientSection::default(),
+        };
+
+        let transport = self.transport.or(file.transport)
+            .unwrap_or_else(|| DEFAULT_TRANSPORT.to_string());
+        let socks_addr = match self.socks_addr.or(file.socks_addr) {
+            Some(addr) => addr,
+            None => DEFAULT_SOCKS_ADDR.parse()?,
+        };
+
+        Ok(ClientEnv {
+            transports: vec![transport],
+            state_location: self.state_dir.or(file.state_dir).unwrap_or_else(|| ".".to_string()),
+            proxy: self.proxy.or(file.proxy),
+            outbound_bind: OutboundBind {
+                v4: file.outbound_bind_v4,
+                v6: file.outbound_bind_v6,
+            },
+            socks_addr: Some(socks_addr),
+        })
+    }
+}
+
+impl ServerFlags {
+    pub fn into_env(self) -> anyhow::Result<ServerEnv> {
+        use anyhow::Context;
+
+        let file = match &self.config {
+            Some(path) => ConfigFile::load(path)?.server.unwrap_or_default(),
+            None => ServerSection::default(),
+        };
+
+        let transport = self.transport.or(file.transport)
+            .unwrap_or_else(|| DEFAULT_TRANSPORT.to_string());
+
+        let bind = if self.bind.is_empty() { file.bind } else { self.bind };
+        if bind.is_empty() {
+            anyhow::bail!("No bind address given; use --bind or `bind` in [server]");
+        }
+
+        let forward = self.forward.or(file.forward)
+            .context("No forward address given; use --forward or `forward` in [server]")?;
+        let state_location = self.state_dir.or(file.state_dir)
+            .context("No state directory given; use --state-dir or `state_dir` in [server]")?;
+
+        let options = merge_options(file.options, &self.options)?;
+
+        let mut transport_options = HashMap::new();
+        if !options.is_empty() {
+            transport_options.insert(transport.clone(), options);
+        }
+
+        Ok(ServerEnv {
+            bind_addrs: bind.into_iter().map(|addr| (transport.clone(), addr)).collect(),
+            transports: vec![transport],
+            orport: forward,
+            extended_orport: None,
+            state_location,
+            transport_options,
+            outbound_bind: OutboundBind {
+                v4: file.outbound_bind_v4,
+                v6: file.outbound_bind_v6,
+            },
+        })
+    }
+}
+
+/// Combines `[server.options]` with `--option KEY=VALUE` flags, which win.
+pub(crate) fn merge_options(
+    file_options: BTreeMap<String, toml::Value>,
+    flag_options: &[String],
+) -> anyhow::Result<PtArgs> {
+    use anyhow::Context;
+
+    let mut options = PtArgs::new();
+    for (key, value) in file_options {
+        let value = match value {
+            toml::Value::String(s) => s,
+            other => other.to_string(),
+        };
+        options.insert(key, value);
+    }
+    for option in flag_options {
+        let (key, value) = option.split_once('=')
+            .context(format!("Option is missing '=': {}", option))?;
+        options.insert(key, value);
+    }
+
+    Ok(options)
+}